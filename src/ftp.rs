@@ -1 +1,10 @@
-pub mod ftpd;
\ No newline at end of file
+pub mod ftpd;
+pub mod auth;
+pub mod command;
+pub mod reply;
+pub mod transfer;
+pub mod tls;
+pub mod storage;
+pub mod audit;
+pub mod limit;
+pub mod hook;
\ No newline at end of file
@@ -0,0 +1,161 @@
+//! Transfer log in the wu-ftpd xferlog format and a session audit log with one JSON object per line.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{Local, Utc};
+use serde::Serialize;
+use super::command;
+
+/// Log files shared by all sessions
+pub struct Logs {
+    xferlog: Mutex<File>,
+    audit: Mutex<File>,
+}
+
+impl Logs {
+    /// Open the log files for appending.
+    pub fn open(xferlog: &Path, audit: &Path) -> io::Result<Self> {
+        let open = |path: &Path| File::options().create(true).append(true).open(path).map(Mutex::new);
+        Ok(Logs { xferlog: open(xferlog)?, audit: open(audit)? })
+    }
+
+    fn write(file: &Mutex<File>, line: &str) {
+        if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+            println!("Log error: {:?}", e);
+        }
+    }
+
+    fn event(&self, peer: SocketAddr, user: Option<&str>, event: &Event) {
+        let record = Record { time: Utc::now().to_rfc3339(), session: peer, user, event };
+        match serde_json::to_string(&record) {
+            Ok(v) => Logs::write(&self.audit, &v),
+            Err(e) => println!("Log error: {:?}", e)
+        }
+    }
+
+    /// A connection closed right after accept, it has no session.
+    pub fn refused(&self, peer: SocketAddr, reason: String) {
+        self.event(peer, None, &Event::Refused { reason });
+    }
+}
+
+/// Direction of a transfer as seen from the server
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// A file transfer for the xferlog
+pub struct Xfer {
+    /// path as the client sees it
+    pub path: String,
+    pub direction: Direction,
+    pub ascii: bool,
+}
+
+/// Session events of the audit log
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connect,
+    Login { anonymous: bool },
+    LoginFailed { user: String },
+    // the address is refused for that long after too many failed logins
+    Ban { seconds: u64 },
+    // connection closed right after accept
+    Refused { reason: String },
+    Command { command: String },
+    Disconnect,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    session: SocketAddr,
+    // the logged in user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Logs of one session
+pub struct SessionLog {
+    logs: Arc<Logs>,
+    peer: SocketAddr,
+    // (user name, anonymous, the email address of anonymous users or the user name)
+    login: Option<(String, bool, String)>,
+}
+
+impl SessionLog {
+    pub fn new(logs: Arc<Logs>, peer: SocketAddr) -> Self {
+        SessionLog { logs, peer, login: None }
+    }
+
+    pub fn set_login(&mut self, user: &str, anonymous: bool, ident: &str) {
+        self.login = Some((user.to_string(), anonymous, ident.to_string()));
+        self.event(Event::Login { anonymous });
+    }
+
+    pub fn clear_login(&mut self) {
+        self.login = None;
+    }
+
+    pub fn event(&self, event: Event) {
+        self.logs.event(self.peer, self.login.as_ref().map(|v| v.0.as_str()), &event);
+    }
+
+    /// A command line from the client as the parser sees it, with the password of PASS hidden
+    pub fn command(&self, line: &[u8]) {
+        let command = redact(&command::line_text(line));
+        println!("[{}] CMD: {}", self.peer, command);
+        self.event(Event::Command { command });
+    }
+
+    /// xferlog line of a finished or interrupted transfer:
+    /// time, seconds, host, bytes, file, type, action, direction, access mode, user, service,
+    /// authentication method, authenticated user id, completion
+    pub fn transfer(&self, xfer: &Xfer, bytes: u64, duration: Duration, complete: bool) {
+        let (anonymous, ident) = match &self.login {
+            Some((_, anonymous, ident)) => (*anonymous, ident.as_str()),
+            None => (false, "*")
+        };
+        let seconds = (duration.as_secs_f64().round() as u64).max(1);
+        // fields are separated by spaces, so a name must not contain any
+        let path: String = xfer.path.chars().map(|c| if c.is_whitespace() { '_' } else { c }).collect();
+        let line = format!("{} {} {} {} {} {} _ {} {} {} ftp 0 * {}",
+            Local::now().format("%a %b %e %H:%M:%S %Y"),
+            seconds,
+            self.peer.ip(),
+            bytes,
+            path,
+            if xfer.ascii { "a" } else { "b" },
+            match xfer.direction { Direction::Incoming => "i", Direction::Outgoing => "o" },
+            if anonymous { "a" } else { "r" },
+            ident.replace(char::is_whitespace, "_"),
+            if complete { "c" } else { "i" });
+        Logs::write(&self.logs.xferlog, &line);
+    }
+}
+
+// also when the session ends with an error
+impl Drop for SessionLog {
+    fn drop(&mut self) {
+        self.event(Event::Disconnect);
+    }
+}
+
+// "PASS secret" -> "PASS ****"
+fn redact(line: &str) -> String {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let verb = line.split(' ').next().unwrap_or_default();
+    if verb.eq_ignore_ascii_case("PASS") {
+        return format!("{} ****", verb)
+    }
+    line.to_string()
+}
@@ -0,0 +1,293 @@
+//! FTP user authentication.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::OsRng;
+use serde::Deserialize;
+
+/// A logged in user
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    /// root directory of the session
+    pub home: PathBuf,
+    pub anonymous: bool,
+    pub permissions: Permissions,
+}
+
+/// What an account may do
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    List,
+    Download,
+    Upload,
+    Overwrite,
+    Delete,
+    Mkdir,
+    Rename,
+}
+
+impl Permission {
+    const ALL: [Permission; 7] = [Permission::List, Permission::Download, Permission::Upload,
+        Permission::Overwrite, Permission::Delete, Permission::Mkdir, Permission::Rename];
+}
+
+/// Permission set of an account, optionally overridden per subdirectory
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    default: Vec<Permission>,
+    // (directory as the client sees it, permissions), most specific first
+    rules: Vec<(String, Vec<Permission>)>,
+    // compare paths ignoring case, like the file system does
+    fold_case: bool,
+}
+
+impl Permissions {
+    pub fn read_only() -> Self {
+        Permissions { default: vec![Permission::List, Permission::Download], rules: Vec::new(), fold_case: false }
+    }
+
+    // Windows file systems ignore case, "/PRIVATE" must not escape a rule for "/private".
+    fn new(default: Vec<Permission>, rules: Vec<RuleEntry>) -> Self {
+        Permissions::with_case(default, rules, !cfg!(unix))
+    }
+
+    fn with_case(default: Vec<Permission>, rules: Vec<RuleEntry>, fold_case: bool) -> Self {
+        let fold = |v: &str| if fold_case { v.to_lowercase() } else { v.to_string() };
+        let mut rules: Vec<(String, Vec<Permission>)> = rules.into_iter()
+            .map(|v| (fold(&format!("/{}", v.path.trim_matches('/'))), v.permissions))
+            .collect();
+        rules.sort_by_key(|v| Reverse(v.0.len()));
+        Permissions { default, rules, fold_case }
+    }
+
+    /// Check a permission on a path relative to the account's home, e.g. "/incoming/a.txt".
+    pub fn allows(&self, path: &str, permission: Permission) -> bool {
+        let path = if self.fold_case { path.to_lowercase() } else { path.to_string() };
+        let rule = self.rules.iter().find(|(dir, _)| {
+            dir == "/" || path == *dir || path.starts_with(&format!("{}/", dir))
+        });
+        match rule {
+            Some((_, v)) => v.contains(&permission),
+            None => self.default.contains(&permission)
+        }
+    }
+}
+
+/// Verified for unknown users, with the parameters of `hash_password`
+const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$chhehottjMJ2Qk7btyMSMQ$reUvvDUEI/rZRq/inJBFuX944rrKnQ+PVCP/Dorc62k";
+
+/// Authentication backend
+pub trait Authenticator: Send + Sync {
+    /// Check the credentials and return the account on success.
+    fn authenticate(&self, user: &str, password: &str) -> Option<Account>;
+}
+
+/// User database file
+///
+/// ```toml
+/// [[user]]
+/// name = "vendor"
+/// password = "$argon2id$v=19$m=19456,t=2,p=1$..."
+/// home = "vendor"
+/// # all permissions when omitted
+/// permissions = []
+///
+/// # write-only drop box
+/// [[user.rule]]
+/// path = "/incoming"
+/// permissions = ["upload"]
+///
+/// # the anonymous account, read-only when omitted
+/// [anonymous]
+/// permissions = ["list", "download"]
+/// ```
+#[derive(Deserialize)]
+struct UserFile {
+    #[serde(default)]
+    user: Vec<UserEntry>,
+    anonymous: Option<AnonymousEntry>,
+}
+
+#[derive(Deserialize)]
+struct UserEntry {
+    name: String,
+    // argon2 PHC string
+    password: String,
+    // relative paths are under the ftp root
+    home: PathBuf,
+    permissions: Option<Vec<Permission>>,
+    #[serde(default)]
+    rule: Vec<RuleEntry>,
+}
+
+#[derive(Deserialize)]
+struct AnonymousEntry {
+    permissions: Option<Vec<Permission>>,
+    #[serde(default)]
+    rule: Vec<RuleEntry>,
+}
+
+#[derive(Deserialize)]
+struct RuleEntry {
+    path: String,
+    permissions: Vec<Permission>,
+}
+
+/// A user of the database
+struct User {
+    password: String,
+    home: PathBuf,
+    permissions: Permissions,
+}
+
+/// Users from a TOML file with argon2 password hashes.
+pub struct FileAuthenticator {
+    users: HashMap<String, User>,
+    anonymous: Permissions,
+}
+
+impl FileAuthenticator {
+    pub fn load(path: &Path, ftp_root: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let file: UserFile = toml::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let mut users = HashMap::new();
+        for entry in file.user {
+            if PasswordHash::new(&entry.password).is_err() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("User {}: password is not an argon2 hash.", entry.name)))
+            }
+            let default = entry.permissions.unwrap_or_else(|| Permission::ALL.to_vec());
+            users.insert(entry.name, User {
+                password: entry.password,
+                home: ftp_root.join(&entry.home),
+                permissions: Permissions::new(default, entry.rule),
+            });
+        }
+        let anonymous = match file.anonymous {
+            Some(v) => {
+                let default = v.permissions.unwrap_or_else(|| Permissions::read_only().default);
+                Permissions::new(default, v.rule)
+            },
+            None => Permissions::read_only()
+        };
+        Ok(FileAuthenticator { users, anonymous })
+    }
+
+    /// No users at all, e.g. when there is no user database.
+    pub fn empty() -> Self {
+        FileAuthenticator { users: HashMap::new(), anonymous: Permissions::read_only() }
+    }
+
+    /// Permissions of the anonymous account
+    pub fn anonymous_permissions(&self) -> Permissions {
+        self.anonymous.clone()
+    }
+}
+
+impl Authenticator for FileAuthenticator {
+    fn authenticate(&self, user: &str, password: &str) -> Option<Account> {
+        let entry = match self.users.get(user) {
+            Some(v) => v,
+            None => {
+                // as slow as a wrong password, the time must not tell whether a user exists
+                let hash = PasswordHash::new(DUMMY_HASH).ok()?;
+                let _ = Argon2::default().verify_password(password.as_bytes(), &hash);
+                return None
+            }
+        };
+        let hash = PasswordHash::new(&entry.password).ok()?;
+        Argon2::default().verify_password(password.as_bytes(), &hash).ok()?;
+        Some(Account {
+            name: user.to_string(),
+            home: entry.home.clone(),
+            anonymous: false,
+            permissions: entry.permissions.clone(),
+        })
+    }
+}
+
+/// Adds the anonymous account to another backend.
+/// RFC1635: the user is "anonymous" or "ftp" and the password is an email address.
+pub struct AnonymousAuthenticator {
+    inner: Box<dyn Authenticator>,
+    home: PathBuf,
+    permissions: Permissions,
+}
+
+impl AnonymousAuthenticator {
+    pub fn new(inner: Box<dyn Authenticator>, home: PathBuf, permissions: Permissions) -> Self {
+        AnonymousAuthenticator { inner, home, permissions }
+    }
+}
+
+impl Authenticator for AnonymousAuthenticator {
+    fn authenticate(&self, user: &str, password: &str) -> Option<Account> {
+        if !is_anonymous(user) {
+            return self.inner.authenticate(user, password)
+        }
+        if !password.contains('@') {
+            return None
+        }
+        Some(Account {
+            name: user.to_ascii_lowercase(),
+            home: self.home.clone(),
+            anonymous: true,
+            permissions: self.permissions.clone(),
+        })
+    }
+}
+
+pub fn is_anonymous(user: &str) -> bool {
+    user.eq_ignore_ascii_case("anonymous") || user.eq_ignore_ascii_case("ftp")
+}
+
+/// Hash a password for the user database.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Could not hash password.")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(fold_case: bool) -> Permissions {
+        let all = vec![Permission::List, Permission::Download, Permission::Upload];
+        let private = RuleEntry { path: "/private/".to_string(), permissions: vec![Permission::List] };
+        Permissions::with_case(all, vec![private], fold_case)
+    }
+
+    #[test]
+    fn rule_applies_to_subtree() {
+        let v = rules(false);
+        assert!(v.allows("/incoming/a.txt", Permission::Upload));
+        assert!(!v.allows("/private", Permission::Download));
+        assert!(!v.allows("/private/a.txt", Permission::Download));
+        assert!(v.allows("/private/a.txt", Permission::List));
+        assert!(v.allows("/privateer/a.txt", Permission::Download));
+    }
+
+    #[test]
+    fn rule_ignores_case_when_folding() {
+        let v = rules(true);
+        assert!(!v.allows("/PRIVATE/a.txt", Permission::Download));
+        assert!(!v.allows("/Private", Permission::Upload));
+        assert!(v.allows("/PRIVATE/a.txt", Permission::List));
+    }
+
+    #[test]
+    fn rule_keeps_case_otherwise() {
+        let v = rules(false);
+        assert!(v.allows("/PRIVATE/a.txt", Permission::Download));
+    }
+}
@@ -0,0 +1,531 @@
+//! Control connection command parser.
+//! RFC959 4.1 verbs plus FEAT/OPTS (RFC2389), EPRT/EPSV (RFC2428),
+//! SIZE/MDTM/REST/MLSx (RFC3659), MFMT, AUTH/PBSZ/PROT (RFC4217) and UTF-8 pathnames (RFC2640).
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use super::transfer::{Mode, Structure};
+
+/// A command line from the client.
+/// Path arguments are taken verbatim after the first space, so they may contain spaces.
+#[derive(Debug)]
+pub enum FtpCommand {
+    // access control
+    User(String),
+    Pass(String),
+    Acct,
+    Cwd(String),
+    Cdup,
+    Smnt,
+    Rein,
+    Quit,
+    // transfer parameters
+    Port(SocketAddr),
+    Pasv,
+    Eprt(SocketAddr),
+    Epsv(Epsv),
+    // true for ASCII, false for image
+    Type(bool),
+    Stru(Structure),
+    Mode(Mode),
+    // service
+    Retr(String),
+    Stor(String),
+    // suggested name, "ftp" when none is given
+    Stou(String),
+    Appe(String),
+    Allo,
+    Rest(u64),
+    Rnfr(String),
+    Rnto(String),
+    Abor,
+    Dele(String),
+    Rmd(String),
+    Mkd(String),
+    Pwd,
+    List(String),
+    Nlst(String),
+    Site(Site),
+    Syst,
+    // path, or empty for the server status
+    Stat(String),
+    // command, or empty for all
+    Help(String),
+    Noop,
+    // extensions
+    Feat,
+    Opts(String),
+    Size(String),
+    Mdtm(String),
+    Mfmt(DateTime<Utc>, String),
+    Mlsd(String),
+    Mlst(String),
+    Auth(String),
+    Pbsz,
+    Prot(String),
+}
+
+/// Verbs the parser knows, for HELP
+pub const COMMANDS: [&str; 48] = [
+    "ABOR", "ACCT", "ALLO", "APPE", "AUTH", "CDUP", "CWD", "DELE", "EPRT", "EPSV", "FEAT", "HELP",
+    "LIST", "MDTM", "MFMT", "MKD", "MLSD", "MLST", "MODE", "NLST", "NOOP", "OPTS", "PASS", "PASV",
+    "PBSZ", "PORT", "PROT", "PWD", "QUIT", "REIN", "REST", "RETR", "RMD", "RNFR", "RNTO", "SITE",
+    "SIZE", "SMNT", "STAT", "STOR", "STOU", "STRU", "SYST", "TYPE", "USER", "XCUP", "XCWD", "XPWD",
+];
+
+/// SITE subcommands
+pub const SITE_COMMANDS: [&str; 3] = ["CHMOD <mode> <path>", "IDLE [<seconds>]", "HELP"];
+
+/// SITE subcommand
+#[derive(Debug)]
+pub enum Site {
+    // octal mode and path
+    Chmod(u32, String),
+    // seconds, or None to show the current value
+    Idle(Option<u64>),
+    Help,
+}
+
+/// EPSV argument
+#[derive(Debug)]
+pub enum Epsv {
+    // any protocol of the control connection
+    Any,
+    // EPSV <net-prt>
+    Protocol(String),
+    // EPSV ALL
+    All,
+}
+
+impl FtpCommand {
+    /// Commands accepted before login
+    pub fn before_login(&self) -> bool {
+        matches!(self, FtpCommand::User(_) | FtpCommand::Pass(_) | FtpCommand::Quit | FtpCommand::Feat
+            | FtpCommand::Opts(_) | FtpCommand::Auth(_) | FtpCommand::Pbsz | FtpCommand::Prot(_))
+    }
+
+    /// Path argument, relative to the working directory unless it starts with "/".
+    /// Empty for the working directory itself.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            FtpCommand::Cwd(v) | FtpCommand::Retr(v) | FtpCommand::Stor(v) | FtpCommand::Stou(v)
+                | FtpCommand::Appe(v) | FtpCommand::Rnfr(v) | FtpCommand::Rnto(v) | FtpCommand::Dele(v)
+                | FtpCommand::Rmd(v) | FtpCommand::Mkd(v) | FtpCommand::List(v) | FtpCommand::Nlst(v)
+                | FtpCommand::Stat(v) | FtpCommand::Size(v) | FtpCommand::Mdtm(v) | FtpCommand::Mfmt(_, v)
+                | FtpCommand::Mlsd(v) | FtpCommand::Mlst(v) | FtpCommand::Site(Site::Chmod(_, v)) => Some(v),
+            _ => None
+        }
+    }
+
+    /// Commands that open a data connection
+    pub fn uses_data_connection(&self) -> bool {
+        matches!(self, FtpCommand::Retr(_) | FtpCommand::Stor(_) | FtpCommand::Stou(_) | FtpCommand::Appe(_)
+            | FtpCommand::List(_) | FtpCommand::Nlst(_) | FtpCommand::Mlsd(_))
+    }
+}
+
+/// Parse one command line.
+/// Errors are the reply code: 500 unknown command, 501 bad argument,
+/// 504 unsupported parameter, 522 unsupported network protocol.
+pub fn parse(line: &[u8]) -> Result<FtpCommand, i32> {
+    let line = line_text(line);
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (verb, arg) = line.split_once(' ').unwrap_or((line, ""));
+    let arg = arg.to_string();
+
+    let command = match verb.to_ascii_uppercase().as_str() {
+        "USER" => FtpCommand::User(required(arg)?),
+        // an empty password is still a password
+        "PASS" => FtpCommand::Pass(arg),
+        "ACCT" => { required(arg)?; FtpCommand::Acct },
+        "CWD" | "XCWD" => FtpCommand::Cwd(required(arg)?),
+        "CDUP" | "XCUP" => FtpCommand::Cdup,
+        "SMNT" => { required(arg)?; FtpCommand::Smnt },
+        "REIN" => FtpCommand::Rein,
+        "QUIT" => FtpCommand::Quit,
+        "PORT" => FtpCommand::Port(parse_port(&arg)?),
+        "PASV" => FtpCommand::Pasv,
+        "EPRT" => FtpCommand::Eprt(parse_eprt(&arg)?),
+        "EPSV" => {
+            match arg.trim().to_ascii_uppercase().as_str() {
+                "" => FtpCommand::Epsv(Epsv::Any),
+                "ALL" => FtpCommand::Epsv(Epsv::All),
+                v => FtpCommand::Epsv(Epsv::Protocol(v.to_string()))
+            }
+        },
+        "TYPE" => FtpCommand::Type(parse_type(&arg)?),
+        "STRU" => {
+            match arg.trim().to_ascii_uppercase().as_str() {
+                "F" => FtpCommand::Stru(Structure::File),
+                "R" => FtpCommand::Stru(Structure::Record),
+                // page structure is not supported
+                "P" => return Err(504),
+                _ => return Err(501)
+            }
+        },
+        "MODE" => {
+            match arg.trim().to_ascii_uppercase().as_str() {
+                "S" => FtpCommand::Mode(Mode::Stream),
+                "B" => FtpCommand::Mode(Mode::Block),
+                "C" => FtpCommand::Mode(Mode::Compressed),
+                "" => return Err(501),
+                _ => return Err(504)
+            }
+        },
+        "RETR" => FtpCommand::Retr(required(arg)?),
+        "STOR" => FtpCommand::Stor(required(arg)?),
+        "STOU" => FtpCommand::Stou(if arg.trim().is_empty() { String::from("ftp") } else { arg }),
+        "APPE" => FtpCommand::Appe(required(arg)?),
+        "ALLO" => FtpCommand::Allo,
+        "REST" => FtpCommand::Rest(arg.trim().parse::<u64>().map_err(|_| 501)?),
+        "RNFR" => FtpCommand::Rnfr(required(arg)?),
+        "RNTO" => FtpCommand::Rnto(required(arg)?),
+        "ABOR" => FtpCommand::Abor,
+        "DELE" => FtpCommand::Dele(required(arg)?),
+        "RMD" | "XRMD" => FtpCommand::Rmd(required(arg)?),
+        "MKD" | "XMKD" => FtpCommand::Mkd(required(arg)?),
+        "PWD" | "XPWD" => FtpCommand::Pwd,
+        "LIST" => FtpCommand::List(ls_path(arg)),
+        "NLST" => FtpCommand::Nlst(ls_path(arg)),
+        "SITE" => FtpCommand::Site(parse_site(&required(arg)?)?),
+        "SYST" => FtpCommand::Syst,
+        "STAT" => FtpCommand::Stat(arg),
+        "HELP" => FtpCommand::Help(arg),
+        "NOOP" => FtpCommand::Noop,
+        "FEAT" => FtpCommand::Feat,
+        "OPTS" => FtpCommand::Opts(required(arg)?),
+        "SIZE" => FtpCommand::Size(required(arg)?),
+        "MDTM" => FtpCommand::Mdtm(required(arg)?),
+        "MFMT" => {
+            // MFMT YYYYMMDDHHMMSS path
+            let (time, path) = arg.split_once(' ').ok_or(501)?;
+            FtpCommand::Mfmt(parse_time_val(time).ok_or(501)?, required(path.to_string())?)
+        },
+        "MLSD" => FtpCommand::Mlsd(arg),
+        "MLST" => FtpCommand::Mlst(arg),
+        "AUTH" => FtpCommand::Auth(required(arg)?),
+        "PBSZ" => { arg.trim().parse::<u32>().map_err(|_| 501)?; FtpCommand::Pbsz },
+        "PROT" => FtpCommand::Prot(required(arg)?),
+        _ => return Err(500)
+    };
+    Ok(command)
+}
+
+/// Syntax of a verb, for HELP <verb>
+pub fn help(verb: &str) -> Option<&'static str> {
+    let text = match verb {
+        "ABOR" => "ABOR (abort the transfer in progress)",
+        "ACCT" => "ACCT <account> (not used by this server)",
+        "ALLO" => "ALLO <size> (no storage needs to be reserved)",
+        "APPE" => "APPE <path> (append to a file)",
+        "AUTH" => "AUTH TLS (secure the control connection)",
+        "CDUP" | "XCUP" => "CDUP (change to the parent directory)",
+        "CWD" | "XCWD" => "CWD <path> (change the working directory)",
+        "DELE" => "DELE <path> (delete a file)",
+        "EPRT" => "EPRT |<protocol>|<address>|<port>| (active data connection)",
+        "EPSV" => "EPSV [<protocol>|ALL] (extended passive data connection)",
+        "FEAT" => "FEAT (list the supported extensions)",
+        "HELP" => "HELP [<command>] (show help)",
+        "LIST" => "LIST [<path>] (list a directory)",
+        "MDTM" => "MDTM <path> (show the modification time)",
+        "MFMT" => "MFMT <YYYYMMDDHHMMSS> <path> (set the modification time)",
+        "MKD" | "XMKD" => "MKD <path> (create a directory)",
+        "MLSD" => "MLSD [<path>] (machine readable directory listing)",
+        "MLST" => "MLST [<path>] (machine readable facts of a file)",
+        "MODE" => "MODE <S|B|C> (transfer mode)",
+        "NLST" => "NLST [<path>] (list file names)",
+        "NOOP" => "NOOP (do nothing)",
+        "OPTS" => "OPTS UTF8 ON (UTF-8 pathnames)",
+        "PASS" => "PASS <password> (log in)",
+        "PASV" => "PASV (passive data connection)",
+        "PBSZ" => "PBSZ 0 (protection buffer size)",
+        "PORT" => "PORT <h1,h2,h3,h4,p1,p2> (active data connection)",
+        "PROT" => "PROT <C|P> (data connection protection)",
+        "PWD" | "XPWD" => "PWD (show the working directory)",
+        "QUIT" => "QUIT (close the connection)",
+        "REIN" => "REIN (not implemented)",
+        "REST" => "REST <offset> (restart the next transfer at an offset)",
+        "RETR" => "RETR <path> (download a file)",
+        "RMD" | "XRMD" => "RMD <path> (remove a directory)",
+        "RNFR" => "RNFR <path> (rename from)",
+        "RNTO" => "RNTO <path> (rename to)",
+        "SITE" => "SITE <command> (server specific commands, see SITE HELP)",
+        "SIZE" => "SIZE <path> (show the size of a file)",
+        "SMNT" => "SMNT <path> (not implemented)",
+        "STAT" => "STAT [<path>] (server status, or list a path)",
+        "STOR" => "STOR <path> (upload a file)",
+        "STOU" => "STOU [<name>] (upload to a unique name)",
+        "STRU" => "STRU <F|R> (file structure)",
+        "SYST" => "SYST (show the system type)",
+        "TYPE" => "TYPE <A|I|L 8> (transfer type)",
+        "USER" => "USER <name> (log in)",
+        _ => return None
+    };
+    Some(text)
+}
+
+/// Text from the client or from a file name on disk.
+/// RFC2640 pathnames are UTF-8, anything else is taken as Latin-1 so no byte is lost.
+pub fn decode_text(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(v) => v.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect()
+    }
+}
+
+/// Command line as the parser sees it, without Telnet commands.
+pub fn line_text(line: &[u8]) -> String {
+    decode_text(&strip_telnet(line))
+}
+
+// Telnet commands such as IP and DM in front of ABOR (RFC959 4.1.3), IAC IAC is a 0xFF byte.
+fn strip_telnet(line: &[u8]) -> Vec<u8> {
+    const IAC: u8 = 255;
+    let mut out = Vec::with_capacity(line.len());
+    let mut iter = line.iter().copied();
+    while let Some(b) = iter.next() {
+        if b != IAC {
+            out.push(b);
+            continue;
+        }
+        match iter.next() {
+            Some(IAC) => out.push(IAC),
+            // WILL, WONT, DO, DONT carry an option byte
+            Some(251..=254) => { iter.next(); },
+            _ => ()
+        }
+    }
+    out
+}
+
+// ls style options such as "-la" are accepted and ignored, "-la /incoming" lists /incoming
+fn ls_path(arg: String) -> String {
+    let mut rest = arg.as_str();
+    while rest.starts_with('-') {
+        rest = rest.split_once(' ').map(|v| v.1.trim_start()).unwrap_or_default();
+    }
+    rest.to_string()
+}
+
+fn required(arg: String) -> Result<String, i32> {
+    if arg.trim().is_empty() {
+        return Err(501)
+    }
+    Ok(arg)
+}
+
+// SITE CHMOD <mode> <path> | IDLE [<seconds>] | HELP
+fn parse_site(value: &str) -> Result<Site, i32> {
+    let (verb, arg) = value.trim_start().split_once(' ').unwrap_or((value.trim(), ""));
+    match verb.to_ascii_uppercase().as_str() {
+        "CHMOD" => {
+            let (mode, path) = arg.trim_start().split_once(' ').ok_or(501)?;
+            let mode = u32::from_str_radix(mode, 8).map_err(|_| 501)?;
+            if mode > 0o7777 {
+                return Err(501)
+            }
+            Ok(Site::Chmod(mode, required(path.to_string())?))
+        },
+        "IDLE" => {
+            match arg.trim() {
+                "" => Ok(Site::Idle(None)),
+                v => Ok(Site::Idle(Some(v.parse::<u64>().map_err(|_| 501)?)))
+            }
+        },
+        "HELP" => Ok(Site::Help),
+        _ => Err(504)
+    }
+}
+
+// PORT h1,h2,h3,h4,p1,p2
+fn parse_port(value: &str) -> Result<SocketAddr, i32> {
+    let fields = value.trim().split(',')
+        .map(|v| v.trim().parse::<u8>())
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| 501)?;
+    match fields[..] {
+        [h1, h2, h3, h4, p1, p2] => {
+            let port = u16::from_be_bytes([p1, p2]);
+            Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(h1, h2, h3, h4)), port))
+        },
+        _ => Err(501)
+    }
+}
+
+// EPRT |<net-prt>|<net-addr>|<tcp-port>|
+fn parse_eprt(value: &str) -> Result<SocketAddr, i32> {
+    let delimiter = value.chars().next().ok_or(501)?;
+    let fields: Vec<&str> = value.split(delimiter).collect();
+    // leading and trailing delimiters give empty first and last fields
+    if fields.len() != 5 || !fields[0].is_empty() || !fields[4].is_empty() {
+        return Err(501)
+    }
+    let ip = match fields[1] {
+        "1" => IpAddr::V4(fields[2].parse::<Ipv4Addr>().map_err(|_| 501)?),
+        "2" => IpAddr::V6(fields[2].parse::<Ipv6Addr>().map_err(|_| 501)?),
+        _ => return Err(522)
+    };
+    let port = fields[3].parse::<u16>().map_err(|_| 501)?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+// Only ASCII non-print and image/8-bit bytes are supported.
+fn parse_type(value: &str) -> Result<bool, i32> {
+    let value = value.to_ascii_uppercase();
+    let mut params = value.split_whitespace();
+    match (params.next(), params.next(), params.next()) {
+        (Some("A"), None | Some("N"), None) => Ok(true),
+        (Some("I"), None, None) | (Some("L"), Some("8"), None) => Ok(false),
+        (Some("A"), Some("T" | "C"), None) | (Some("E"), _, None) | (Some("L"), Some(_), None) => Err(504),
+        _ => Err(501)
+    }
+}
+
+// MFMT time-val, fractions of a second are ignored
+fn parse_time_val(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.split('.').next()?;
+    let time = NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%S").ok()?;
+    Some(time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(line: &str) -> Option<i32> {
+        parse(line.as_bytes()).err()
+    }
+
+    fn path(line: &[u8]) -> String {
+        parse(line).unwrap().path().unwrap().to_string()
+    }
+
+    #[test]
+    fn port() {
+        let addr = "192.168.1.2:1025".parse::<SocketAddr>().unwrap();
+        assert!(matches!(parse(b"PORT 192,168,1,2,4,1\r\n"), Ok(FtpCommand::Port(v)) if v == addr));
+        assert!(matches!(parse(b"port 192, 168, 1, 2, 4, 1\r\n"), Ok(FtpCommand::Port(v)) if v == addr));
+        assert_eq!(code("PORT 192,168,1,2,4"), Some(501));
+        assert_eq!(code("PORT 192,168,1,2,4,1,0"), Some(501));
+        assert_eq!(code("PORT 192,168,1,256,4,1"), Some(501));
+        assert_eq!(code("PORT"), Some(501));
+    }
+
+    #[test]
+    fn eprt() {
+        let v4 = "10.0.0.1:6446".parse::<SocketAddr>().unwrap();
+        let v6 = "[2001:db8::1]:6446".parse::<SocketAddr>().unwrap();
+        assert!(matches!(parse(b"EPRT |1|10.0.0.1|6446|"), Ok(FtpCommand::Eprt(v)) if v == v4));
+        assert!(matches!(parse(b"EPRT |2|2001:db8::1|6446|"), Ok(FtpCommand::Eprt(v)) if v == v6));
+        // any delimiter the client likes
+        assert!(matches!(parse(b"EPRT !1!10.0.0.1!6446!"), Ok(FtpCommand::Eprt(v)) if v == v4));
+        assert_eq!(code("EPRT |3|10.0.0.1|6446|"), Some(522));
+        assert_eq!(code("EPRT |1|2001:db8::1|6446|"), Some(501));
+        assert_eq!(code("EPRT |1|10.0.0.1|70000|"), Some(501));
+        assert_eq!(code("EPRT |1|10.0.0.1|6446"), Some(501));
+        assert_eq!(code("EPRT"), Some(501));
+    }
+
+    #[test]
+    fn epsv() {
+        assert!(matches!(parse(b"EPSV"), Ok(FtpCommand::Epsv(Epsv::Any))));
+        assert!(matches!(parse(b"EPSV all"), Ok(FtpCommand::Epsv(Epsv::All))));
+        assert!(matches!(parse(b"EPSV 2"), Ok(FtpCommand::Epsv(Epsv::Protocol(v))) if v == "2"));
+    }
+
+    #[test]
+    fn type_stru_mode() {
+        for (line, ascii) in [("TYPE A", true), ("type a n", true), ("TYPE I", false), ("TYPE L 8", false)] {
+            assert!(matches!(parse(line.as_bytes()), Ok(FtpCommand::Type(v)) if v == ascii), "{}", line);
+        }
+        for line in ["TYPE A T", "TYPE A C", "TYPE E", "TYPE L 16"] {
+            assert_eq!(code(line), Some(504), "{}", line);
+        }
+        for line in ["TYPE", "TYPE X", "TYPE I N", "TYPE A N X"] {
+            assert_eq!(code(line), Some(501), "{}", line);
+        }
+        assert!(matches!(parse(b"STRU r"), Ok(FtpCommand::Stru(Structure::Record))));
+        assert_eq!(code("STRU P"), Some(504));
+        assert_eq!(code("STRU X"), Some(501));
+        assert!(matches!(parse(b"MODE b"), Ok(FtpCommand::Mode(Mode::Block))));
+        assert_eq!(code("MODE X"), Some(504));
+        assert_eq!(code("MODE"), Some(501));
+    }
+
+    #[test]
+    fn mfmt() {
+        let time = "2020-01-02T03:04:05Z".parse::<DateTime<Utc>>().unwrap();
+        assert!(matches!(parse(b"MFMT 20200102030405 a b.txt"), Ok(FtpCommand::Mfmt(t, v)) if t == time && v == "a b.txt"));
+        // fractions are ignored
+        assert!(matches!(parse(b"MFMT 20200102030405.123 a.txt"), Ok(FtpCommand::Mfmt(t, _)) if t == time));
+        assert_eq!(code("MFMT 20201302030405 a.txt"), Some(501));
+        assert_eq!(code("MFMT 20200102030405"), Some(501));
+        assert_eq!(code("MFMT 20200102030405 "), Some(501));
+    }
+
+    #[test]
+    fn site() {
+        assert!(matches!(parse(b"SITE CHMOD 644 my file"), Ok(FtpCommand::Site(Site::Chmod(0o644, v))) if v == "my file"));
+        assert!(matches!(parse(b"site chmod 4755 a"), Ok(FtpCommand::Site(Site::Chmod(0o4755, _)))));
+        assert_eq!(code("SITE CHMOD 19 a"), Some(501));
+        assert_eq!(code("SITE CHMOD 17777 a"), Some(501));
+        assert_eq!(code("SITE CHMOD 644"), Some(501));
+        assert!(matches!(parse(b"SITE IDLE"), Ok(FtpCommand::Site(Site::Idle(None)))));
+        assert!(matches!(parse(b"SITE IDLE 600"), Ok(FtpCommand::Site(Site::Idle(Some(600))))));
+        assert_eq!(code("SITE IDLE soon"), Some(501));
+        assert!(matches!(parse(b"SITE HELP"), Ok(FtpCommand::Site(Site::Help))));
+        assert_eq!(code("SITE EXEC ls"), Some(504));
+        assert_eq!(code("SITE"), Some(501));
+    }
+
+    #[test]
+    fn reply_codes() {
+        assert_eq!(code("XYZZY"), Some(500));
+        assert_eq!(code(""), Some(500));
+        assert_eq!(code("RETR"), Some(501));
+        assert_eq!(code("RETR  "), Some(501));
+        assert_eq!(code("REST -1"), Some(501));
+        assert_eq!(code("PBSZ x"), Some(501));
+        assert!(matches!(parse(b"REST 100\r\n"), Ok(FtpCommand::Rest(100))));
+        // an empty password is a password
+        assert!(matches!(parse(b"PASS\r\n"), Ok(FtpCommand::Pass(v)) if v.is_empty()));
+        assert!(matches!(parse(b"STOU"), Ok(FtpCommand::Stou(v)) if v == "ftp"));
+    }
+
+    #[test]
+    fn telnet() {
+        // IP and synch (IAC DM) in front of ABOR
+        assert!(matches!(parse(b"\xff\xf4\xff\xf2ABOR\r\n"), Ok(FtpCommand::Abor)));
+        // option negotiation carries an option byte
+        assert!(matches!(parse(b"\xff\xfb\x01NOOP\r\n"), Ok(FtpCommand::Noop)));
+        // IAC IAC is a 0xFF byte
+        assert_eq!(path(b"RETR a\xff\xffb\r\n"), "a\u{ff}b");
+        assert_eq!(line_text(b"PASS \xff\xf4secret\r\n"), "PASS secret\r\n");
+    }
+
+    #[test]
+    fn text_encoding() {
+        assert_eq!(path("RETR café.txt\r\n".as_bytes()), "café.txt");
+        // not UTF-8, taken as Latin-1
+        assert_eq!(path(b"RETR caf\xe9.txt\r\n"), "caf\u{e9}.txt");
+        assert_eq!(decode_text(b"\x80\xff"), "\u{80}\u{ff}");
+    }
+
+    #[test]
+    fn paths_with_spaces() {
+        assert_eq!(path(b"STOR my file.txt\r\n"), "my file.txt");
+        assert_eq!(path(b"CWD  leading space\r\n"), " leading space");
+        assert_eq!(path(b"DELE trailing \r\n"), "trailing ");
+        assert_eq!(path(b"RNTO a  b\n"), "a  b");
+        assert_eq!(path(b"NLST my dir"), "my dir");
+    }
+
+    #[test]
+    fn ls_options() {
+        assert_eq!(path(b"LIST -la /incoming\r\n"), "/incoming");
+        assert_eq!(path(b"LIST -l  -a my dir\r\n"), "my dir");
+        assert_eq!(path(b"NLST -la\r\n"), "");
+        assert_eq!(path(b"LIST\r\n"), "");
+        assert_eq!(path(b"LIST dir-with-dash\r\n"), "dir-with-dash");
+    }
+}
@@ -1,285 +1,1879 @@
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::fs::File;
-use std::path::PathBuf;
-use std::io::{Write, BufRead, BufReader, Read};
-use tokio::io::Interest;
-use tokio::net::{TcpStream as TokioTcpStream, TcpSocket};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use chrono::{DateTime, Local, Utc};
+use clap::Args;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream as TokioTcpStream, TcpSocket};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use super::auth::{self, Account, AnonymousAuthenticator, Authenticator, FileAuthenticator, Permission};
+use super::command::{self, Epsv, FtpCommand, Site};
+use super::reply::Reply;
+use super::transfer::{Decoder, Encoder, Mode, Structure};
+use super::tls::{self, FtpStream};
+use super::storage::{Backend, Entry, MemoryStorage, Storage, WriteMode};
+use super::audit::{Direction, Event, Logs, SessionLog, Xfer};
+use super::limit::{Client, Limiter, Refused};
+use super::hook::{self, Hook, HookEvent, Notice};
 
 //const FTP_CMD: [&str; 11] = ["USER", "PASS", "QUIT", "PORT", "TYPE", "MODE", "STRU", "RETR", "STOR", "NOOP", "OPTS"];
 
-pub fn run() {
+/// ftpd settings
+#[derive(Args, Debug, Clone)]
+pub struct Config {
+    /// maximum number of concurrent sessions (0 = unlimited)
+    #[arg(long, default_value_t = 10)]
+    pub max_sessions: usize,
+    /// addresses the control connection listens on, IPv4 or IPv6 (repeatable)
+    #[arg(long, default_value = "127.0.0.1")]
+    pub listen: Vec<IpAddr>,
+    /// port of the control connection
+    #[arg(long, default_value_t = 21)]
+    pub port: u16,
+    /// source port of active mode data connections (0 = any ephemeral port, 20 as in RFC959 needs root)
+    #[arg(long, default_value_t = 0)]
+    pub active_port: u16,
+    /// first port of the passive mode range (0 = any ephemeral port)
+    #[arg(long, default_value_t = 0)]
+    pub pasv_min_port: u16,
+    /// last port of the passive mode range
+    #[arg(long, default_value_t = 0)]
+    pub pasv_max_port: u16,
+    /// address advertised in PASV replies, e.g. the public address behind NAT
+    #[arg(long)]
+    pub pasv_address: Option<Ipv4Addr>,
+    /// user database (TOML, argon2 password hashes), default: ftp-users.toml next to the ftp root
+    #[arg(long)]
+    pub users: Option<PathBuf>,
+    /// allow anonymous login with an email address as password
+    #[arg(long)]
+    pub anonymous: bool,
+    /// home of the anonymous account, relative to the ftp root
+    #[arg(long, default_value = "pub")]
+    pub anonymous_home: PathBuf,
+    /// certificate chain (PEM) for AUTH TLS
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// private key (PEM) of the certificate
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// refuse USER and unprotected data connections before AUTH TLS
+    #[arg(long, requires = "tls_cert")]
+    pub require_tls: bool,
+    /// refuse protected data connections that do not resume the control connection's TLS session
+    #[arg(long, requires = "tls_cert")]
+    pub require_tls_reuse: bool,
+    /// seconds without a command before the session is closed with 421 (0 = never)
+    #[arg(long, default_value_t = 300)]
+    pub idle_timeout: u64,
+    /// seconds to wait for a data connection before 425
+    #[arg(long, default_value_t = 30)]
+    pub data_timeout: u64,
+    /// control connections per client address (0 = unlimited)
+    #[arg(long, default_value_t = 5)]
+    pub max_connections_per_ip: usize,
+    /// failed logins in one session before it is closed (0 = unlimited)
+    #[arg(long, default_value_t = 3)]
+    pub max_login_failures: u32,
+    /// failed logins from one address before it is banned (0 = never)
+    #[arg(long, default_value_t = 10)]
+    pub ban_after: u32,
+    /// seconds of the first ban of an address, doubled for every further ban
+    #[arg(long, default_value_t = 60)]
+    pub ban_time: u64,
+    /// seconds to wait before answering a failed login with 530
+    #[arg(long, default_value_t = 2)]
+    pub login_delay: u64,
+    /// wu-ftpd style transfer log, default: xferlog next to the ftp root
+    #[arg(long)]
+    pub xferlog: Option<PathBuf>,
+    /// session audit log (JSON lines), default: ftp-audit.log next to the ftp root
+    #[arg(long)]
+    pub audit_log: Option<PathBuf>,
+    /// run a command or POST JSON on an event: <upload|download|delete|rename|login>=<command or http://host:port/path> (repeatable)
+    #[arg(long = "hook", value_name = "EVENT=COMMAND|URL")]
+    pub hooks: Vec<Hook>,
+    /// seconds before a hook is stopped
+    #[arg(long, default_value_t = 30)]
+    pub hook_timeout: u64,
+    /// keep files in memory instead of the ftp root, they are lost on exit
+    #[arg(long)]
+    pub memory_storage: bool,
+    /// read a password from stdin, print its hash for the user database and exit
+    #[arg(long)]
+    pub hash_password: bool,
+}
+
+/// Directory listing formats
+#[derive(Clone, Copy, PartialEq)]
+enum ListFormat {
+    // LIST: ls -l style
+    Long,
+    // NLST: names only
+    Names,
+    // MLSD: RFC3659 facts
+    Machine,
+}
+
+/// FEAT reply (RFC2389), TLS features are added when configured
+const FEATURES: [&str; 9] = ["EPRT", "EPSV", "MDTM", "MFMT", "MLST type*;size*;modify*;perm*;unique*;",
+    "REST STREAM", "SIZE", "TVFS", "UTF8"];
+
+/// Size of the data connection copy buffer
+const DATA_BUF_SIZE: usize = 64 * 1024;
+
+/// Why a transfer stopped before completion
+#[derive(Debug)]
+enum TransferError {
+    // data connection failed -> 426
+    Connection(io::Error),
+    // local file could not be read or written -> 451
+    Local(io::Error),
+}
+
+/// Range of SITE IDLE in seconds
+const SITE_IDLE: std::ops::RangeInclusive<u64> = 30..=7200;
+
+/// Longest command line, the rest of a longer one is dropped up to the next LF
+const MAX_LINE: usize = 8 * 1024;
+
+/// Settings and services shared by all sessions
+struct Server {
+    config: Config,
+    authenticator: Arc<dyn Authenticator>,
+    backend: Backend,
+    logs: Arc<Logs>,
+    // shared TLS settings, every session makes its own acceptor from them
+    tls: Option<TlsAcceptor>,
+}
+
+/// TCP control connection with urgent data inline, ABOR is often sent as urgent data
+struct UrgentInline(TokioTcpStream);
+
+impl UrgentInline {
+    fn new(stream: TokioTcpStream) -> io::Result<Self> {
+        socket2::SockRef::from(&stream).set_out_of_band_inline(true)?;
+        Ok(UrgentInline(stream))
+    }
+}
+
+impl AsyncRead for UrgentInline {
+    // Readiness is only cleared on WouldBlock. A short read can stop at the urgent
+    // mark of an ABOR sent as urgent data, with the rest of the line still queued.
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let stream = &self.get_mut().0;
+        loop {
+            ready!(stream.poll_read_ready(cx))?;
+            match stream.try_read(buf.initialize_unfilled()) {
+                Ok(n) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()))
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Poll::Ready(Err(e))
+            }
+        }
+    }
+}
+
+impl AsyncWrite for UrgentInline {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
+
+/// Sending side of the control connection
+type ControlStream<S> = WriteHalf<FtpStream<S>>;
+
+/// Receiving side of the control connection
+struct ControlReader<S> {
+    reader: BufReader<ReadHalf<FtpStream<S>>>,
+    // partial line, kept when a read is cancelled
+    line: Vec<u8>,
+    // the line is longer than MAX_LINE, dropping it
+    overlong: bool,
+    // commands that arrived during a transfer
+    queued: VecDeque<Vec<u8>>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ControlReader<S> {
+    fn new(reader: ReadHalf<FtpStream<S>>) -> Self {
+        ControlReader { reader: BufReader::new(reader), line: Vec::new(), overlong: false, queued: VecDeque::new() }
+    }
+
+    /// Next command line, queued ones first. None when the client closed the connection.
+    async fn next_command(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.queued.pop_front() {
+            Some(v) => Ok(Some(v)),
+            None => self.read_line().await
+        }
+    }
+
+    /// Read a line from the connection. Cancel safe, a partial line stays in the buffer.
+    /// A line longer than MAX_LINE is returned empty, a line from the client always has its LF.
+    async fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let buf = self.reader.fill_buf().await?;
+            let (end, complete) = match buf.iter().position(|v| *v == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (buf.len(), false)
+            };
+            // client closed the connection, a partial last line still counts
+            if end == 0 {
+                if self.line.is_empty() && !self.overlong {
+                    return Ok(None)
+                }
+                self.overlong = false;
+                return Ok(Some(std::mem::take(&mut self.line)))
+            }
+            if !self.overlong {
+                self.line.extend_from_slice(&buf[..end]);
+            }
+            self.reader.consume(end);
+            if self.line.len() > MAX_LINE {
+                self.line.clear();
+                self.overlong = true;
+            }
+            if complete {
+                self.overlong = false;
+                return Ok(Some(std::mem::take(&mut self.line)))
+            }
+        }
+    }
+}
+
+/// A running transfer as seen from the control connection
+struct Progress {
+    // bytes of the local file transferred so far
+    bytes: AtomicU64,
+    // 110 restart marker replies
+    marks: mpsc::UnboundedSender<Reply>,
+}
+
+impl Progress {
+    fn new() -> (Self, mpsc::UnboundedReceiver<Reply>) {
+        let (marks, receiver) = mpsc::unbounded_channel();
+        (Progress { bytes: AtomicU64::new(0), marks }, receiver)
+    }
+}
+
+/// Session state machine (RFC959 6)
+enum State {
+    // USER expected
+    AwaitingUser,
+    // USER received, PASS expected
+    AwaitingPass(String),
+    LoggedIn { account: Account, pending: Pending },
+}
+
+/// A command waiting for the one that has to follow it
+enum Pending {
+    None,
+    // RNFR accepted, RNTO expected
+    Rename(String),
+    // REST accepted, RETR, STOR or APPE expected
+    Restart(u64),
+}
+
+impl State {
+    fn account(&self) -> Option<&Account> {
+        match self {
+            State::LoggedIn { account, .. } => Some(account),
+            _ => None
+        }
+    }
+
+    /// Leave the pending state, it only applies to the command right after it.
+    fn take_pending(&mut self) -> Pending {
+        match self {
+            State::LoggedIn { pending, .. } => std::mem::replace(pending, Pending::None),
+            _ => Pending::None
+        }
+    }
+
+    fn set_pending(&mut self, value: Pending) {
+        if let State::LoggedIn { pending, .. } = self {
+            *pending = value;
+        }
+    }
+}
+
+/// How the next data connection is established
+enum DataConn {
+    None,
+    // PORT: connect to the client
+    Active(SocketAddr),
+    // PASV/EPSV: wait for the client
+    Passive(TcpListener),
+}
+
+pub fn run(config: Config) {
+    if config.hash_password {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password).expect("Could not read password.");
+        println!("{}", auth::hash_password(password.trim_end_matches(['\r', '\n'])));
+        return;
+    }
+
     // Create ftp root directory
     let ftp_root: PathBuf = dirs::desktop_dir().unwrap().join("ftp-root");
     if !ftp_root.exists() {
         std::fs::create_dir(&ftp_root).expect("Could not create directory.");
     }
 
-    // listen ftp connection
-    let listener = TcpListener::bind("127.0.0.1:21").unwrap();
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        handle_control_connection(stream, ftp_root);
-        break;
+    // user database
+    let users = config.users.clone().unwrap_or_else(|| ftp_root.with_file_name("ftp-users.toml"));
+    let users = if users.exists() {
+        FileAuthenticator::load(&users, &ftp_root).expect("Could not load user database.")
+    } else {
+        println!("No user database at {:?}", users);
+        FileAuthenticator::empty()
+    };
+    let authenticator: Arc<dyn Authenticator> = if config.anonymous {
+        let permissions = users.anonymous_permissions();
+        Arc::new(AnonymousAuthenticator::new(Box::new(users), ftp_root.join(&config.anonymous_home), permissions))
+    } else {
+        Arc::new(users)
+    };
+
+    let backend = match config.memory_storage {
+        true => Backend::Memory(ftp_root.clone(), MemoryStorage::new()),
+        false => Backend::Local
+    };
+
+    let xferlog = config.xferlog.clone().unwrap_or_else(|| ftp_root.with_file_name("xferlog"));
+    let audit_log = config.audit_log.clone().unwrap_or_else(|| ftp_root.with_file_name("ftp-audit.log"));
+    let logs = Logs::open(&xferlog, &audit_log).expect("Could not open log files.");
+
+    // FTPS certificate
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load_acceptor(cert, key).expect("Could not load TLS certificate.")),
+        _ => None
+    };
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(serve(config, authenticator, backend, logs, tls));
+}
+
+async fn serve(config: Config, authenticator: Arc<dyn Authenticator>, backend: Backend, logs: Logs, tls: Option<TlsAcceptor>) {
+    let limiter = Arc::new(Limiter::new(config.max_sessions, config.max_connections_per_ip, config.ban_after, Duration::from_secs(config.ban_time)));
+    let server = Arc::new(Server { config, authenticator, backend, logs: Arc::new(logs), tls });
+    let config = &server.config;
+
+    // listen ftp connection on every address
+    let mut tasks = Vec::new();
+    for ip in config.listen.iter() {
+        let addr = SocketAddr::new(*ip, config.port);
+        let listener = match TcpListener::bind(addr).await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Could not listen on {}: {:?}", addr, e);
+                continue;
+            }
+        };
+        tasks.push(tokio::spawn(accept_loop(listener, limiter.clone(), server.clone())));
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+}
+
+async fn accept_loop(listener: TcpListener, limiter: Arc<Limiter>, server: Arc<Server>) {
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Accept error: {:?}", e);
+                continue;
+            }
+        };
+        // IPv4 clients of a dual-stack listener are IPv4 clients
+        let peer = canonical(peer);
+
+        // Server is full, banned address or too many connections from it -> 421 and close
+        let client = match limiter.connect(peer) {
+            Ok(v) => v,
+            Err(refused) => {
+                let (reason, reply) = match refused {
+                    Refused::Banned(v) => (format!("banned for {}s", v.as_secs_f64().ceil()), Reply::text(421, "Too many failed logins, try again later.")),
+                    Refused::TooManyConnections => ("too many connections".to_string(), Reply::text(421, "Too many connections from your address.")),
+                    Refused::ServerFull => ("session limit reached".to_string(), Reply::new(421))
+                };
+                println!("Rejecting {}: {}", peer, reason);
+                server.logs.refused(peer, reason);
+                let _ = stream.write_all(&reply.to_bytes()).await;
+                continue;
+            }
+        };
+
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_tcp_connection(stream, client, server).await {
+                println!("Session {} error: {:?}", peer, e);
+            }
+        });
     }
 }
 
-fn handle_control_connection(mut stream: TcpStream, fs_path: PathBuf) {
+async fn handle_tcp_connection(stream: TokioTcpStream, client: Client, server: Arc<Server>) -> io::Result<()> {
+    // server side address of the control connection, decides the family of data connections
+    let local = canonical(stream.local_addr()?);
+    handle_control_connection(UrgentInline::new(stream)?, local, client, server).await
+}
+
+/// Run a session on a control connection, `local` is the server side address of it.
+async fn handle_control_connection<S>(stream: S, local: SocketAddr, client: Client, server: Arc<Server>) -> io::Result<()>
+        where S: AsyncRead + AsyncWrite + Unpin {
+    let peer = client.peer;
+    let config = &server.config;
+    let mut log = SessionLog::new(server.logs.clone(), peer);
+    let (reader, mut stream) = tokio::io::split(FtpStream::Plain(stream));
+    log.event(Event::Connect);
     // Server reply -> ok
-    stream.write_all(get_reply_message(220)).unwrap();
-    // buffer reader
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
-    // destination address
-    let mut dst_addr = String::new();
-    // ftp user
-    let user = "ftp";
-    // ftp user passwd
-    let passwd = "ftp";
-    // command sequence
-    let mut correct_sequence = false;
-    // runtime
-    let rt = tokio::runtime::Runtime::new().unwrap();
+    reply(&mut stream, 220).await?;
+    let mut control = ControlReader::new(reader);
+    // SITE IDLE changes it for the session
+    let mut idle_timeout = match config.idle_timeout {
+        0 => Duration::MAX,
+        n => Duration::from_secs(n)
+    };
+    // data connection
+    let mut data_conn = DataConn::None;
+    // EPSV ALL received, only EPSV is accepted from now on
+    let mut epsv_all = false;
+    // login state, RNFR and REST pending
+    let mut state = State::AwaitingUser;
+    // failed PASS in this session
+    let mut login_failures = 0;
+    // files of the session, the home of the account after login
+    let mut storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    // current working directory, relative to the session root
+    let mut cwd = String::from("/");
+    // representation type, ASCII is the default
+    let mut ascii_type = true;
+    // transmission mode and file structure
+    let mut mode = Mode::Stream;
+    let mut stru = Structure::File;
+    // acceptor of the control connection after AUTH TLS, PBSZ received, PROT P in effect
+    let mut session_tls: Option<TlsAcceptor> = None;
+    let mut pbsz = false;
+    let mut data_protected = false;
 
     loop {
-        let mut buf = Vec::new();
-        if let Err(e) = reader.read_until(10, &mut buf) {
-            println!("Buffer reading error: {:?}", e);
-            break;
+        let buf = match timeout(idle_timeout, control.next_command()).await {
+            Ok(Ok(Some(v))) => v,
+            // client closed the connection
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => {
+                println!("Buffer reading error: {:?}", e);
+                break;
+            },
+            Err(_) => {
+                println!("[{}] Idle timeout", peer);
+                reply_text(&mut stream, 421, "Idle timeout, closing control connection.").await?;
+                break;
+            }
+        };
+
+        if buf.is_empty() {
+            println!("[{}] Command line too long", peer);
+            reply_text(&mut stream, 500, "Command line too long.").await?;
+            continue;
         }
+        log.command(&buf);
+        let command = match command::parse(&buf) {
+            Ok(v) => v,
+            Err(522) => {
+                reply_unsupported_family(&mut stream, local.ip()).await?;
+                continue;
+            },
+            Err(code) => {
+                reply(&mut stream, code).await?;
+                continue;
+            }
+        };
 
-        // whitespace delimited iterator
-        let mut iter = buf.split(|b| b == &32u8);
-        
-        if let Some(v) =  iter.next() {
-            // ftp command
-            let buf = v.to_ascii_uppercase();
-            let buf = buf.as_slice();
-            let cmd = std::str::from_utf8(buf).unwrap();
-            let cmd = cmd.trim();
-            println!("CMD: {}", cmd);
-
-            // ftp value
-            let value = if let Some(v) = iter.next() {
-                let val = std::str::from_utf8(v).unwrap();
-                println!("VAL: {}", val);
-                val.trim()
-            } else {
-                ""
-            };
-            
-            // Processing by ftp command
-            match cmd {
-                "USER" => {
-                    if user == value {
-                        correct_sequence = true;
-                        stream.write_all(get_reply_message(331)).unwrap()
-                    } else {
-                        stream.write_all(get_reply_message(530)).unwrap()
-                    }
-                },
-                "PASS" => {
-                    if !correct_sequence {
-                        stream.write_all(get_reply_message(503)).unwrap();
+        // RNTO must directly follow RNFR
+        // RFC3659: REST must directly precede RETR, STOR or APPE
+        let (pending_rename, restart) = match state.take_pending() {
+            Pending::Rename(v) => (Some(v), None),
+            Pending::Restart(v) => (None, Some(v)),
+            Pending::None => (None, None)
+        };
+
+        // Only login commands before login
+        if state.account().is_none() && !command.before_login() {
+            reply(&mut stream, 530).await?;
+            continue;
+        }
+        // RFC4217 10: data connections have to be protected as well
+        if config.require_tls && !data_protected && command.uses_data_connection() {
+            reply_text(&mut stream, 521, "Data connections must be protected, use PROT P.").await?;
+            continue;
+        }
+
+        // Every path argument is resolved here, escapes from the session root are refused.
+        let path = match command.path().map(|v| resolve_path(&cwd, v)) {
+            Some(Some(v)) => v,
+            Some(None) => {
+                println!("[{}] Path outside the root refused", peer);
+                reply(&mut stream, 550).await?;
+                continue;
+            },
+            None => String::new()
+        };
+
+        // Processing by ftp command
+        match &command {
+            FtpCommand::User(value) => {
+                if config.require_tls && session_tls.is_none() {
+                    reply_text(&mut stream, 530, "TLS required, use AUTH TLS.").await?;
+                    continue;
+                }
+                // A new USER ends the current login.
+                state = State::AwaitingPass(value.to_string());
+                log.clear_login();
+                // Always ask for a password so user names cannot be probed.
+                if auth::is_anonymous(value) && config.anonymous {
+                    reply_text(&mut stream, 331, "Anonymous login ok, send your email address as password.").await?
+                } else {
+                    reply(&mut stream, 331).await?
+                }
+            },
+            FtpCommand::Pass(value) => {
+                let name = match &state {
+                    State::AwaitingPass(v) => v.clone(),
+                    _ => {
+                        reply(&mut stream, 503).await?;
                         continue;
                     }
-                    if passwd == value {
-                        stream.write_all(get_reply_message(230)).unwrap()
-                    } else {
-                        stream.write_all(get_reply_message(530)).unwrap()
-                    }
-                },
-                "PORT" => {
-                    let addrs: Vec<&str> = value.split(",").collect();
-                    let addr = format!("{}.{}.{}.{}", addrs[0], addrs[1], addrs[2], addrs[3]);
-                    let port = addrs[4].parse::<i32>().unwrap() * 256 + addrs[5].parse::<i32>().unwrap();
-                    dst_addr = format!("{}:{}", addr, port);
-                    println!("{:?}", dst_addr);
-                    stream.write_all(get_reply_message(200)).unwrap()
-                },
-                "RETR" => {
-                    stream.write_all(get_reply_message(150)).unwrap();
-                    let file = match control_filesystem("read", fs_path.join(value)) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            println!("Error: {:?}", e);
-                            continue;
+                };
+                state = State::AwaitingUser;
+                // another session may have caused a ban
+                if client.banned().is_some() {
+                    reply_text(&mut stream, 421, "Too many failed logins, try again later.").await?;
+                    break;
+                }
+                // Password hashing is slow, keep it off the runtime threads.
+                let users = server.authenticator.clone();
+                let (user, password) = (name.clone(), value.to_string());
+                let result = tokio::task::spawn_blocking(move || users.authenticate(&user, &password)).await;
+                match result {
+                    Ok(Some(v)) => {
+                        storage = match server.backend.open(&v.home) {
+                            Ok(v) => v,
+                            Err(e) => {
+                                println!("Error: {:?}", e);
+                                reply(&mut stream, 530).await?;
+                                continue;
+                            }
+                        };
+                        if v.anonymous {
+                            println!("[{}] Anonymous login ({})", peer, value);
+                        } else {
+                            println!("[{}] Logged in as {}", peer, v.name);
                         }
-                    };
-                    rt.block_on(handle_w_data_connection(dst_addr.clone(), file));
-                    stream.write_all(get_reply_message(226)).unwrap();
-                },
-                "LIST" => {
-                    stream.write_all(get_reply_message(150)).unwrap();
-                    let ls = match control_filesystem("ls", fs_path.clone()) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            println!("Error: {:?}", e);
-                            continue;
+                        cwd = String::from("/");
+                        // anonymous users are known by their email address in the xferlog
+                        log.set_login(&v.name, v.anonymous, if v.anonymous { value } else { &v.name });
+                        fire_hook(config, notice(HookEvent::Login, Some(&v), peer, None));
+                        state = State::LoggedIn { account: v, pending: Pending::None };
+                        reply(&mut stream, 230).await?
+                    },
+                    _ => {
+                        log.event(Event::LoginFailed { user: name });
+                        login_failures += 1;
+                        let ban = client.login_failed();
+                        // slows down guessing
+                        tokio::time::sleep(Duration::from_secs(config.login_delay)).await;
+                        reply(&mut stream, 530).await?;
+                        if let Some(v) = ban {
+                            println!("[{}] Banned for {}s after failed logins", peer, v.as_secs());
+                            log.event(Event::Ban { seconds: v.as_secs() });
+                            reply_text(&mut stream, 421, "Too many failed logins, try again later.").await?;
+                            break;
+                        }
+                        if config.max_login_failures != 0 && login_failures >= config.max_login_failures {
+                            println!("[{}] Too many failed logins", peer);
+                            reply_text(&mut stream, 421, "Too many failed logins.").await?;
+                            break;
                         }
-                    };
-                    rt.block_on(handle_w_data_connection(dst_addr.clone(), ls));
-                    stream.write_all(get_reply_message(226)).unwrap();
+                    }
                 }
-                "TYPE" => {
-                    stream.write_all(get_reply_message(200)).unwrap()
-                },
-                "STOR" => {
-                    stream.write_all(get_reply_message(150)).unwrap();
-                    if let Err(e) = rt.block_on(handle_r_data_connection(dst_addr.clone(), fs_path.join(value))) {
+            },
+            FtpCommand::Auth(value) => {
+                let shared = match &server.tls {
+                    Some(v) => v,
+                    None => {
+                        reply_text(&mut stream, 431, "TLS is not configured.").await?;
+                        continue;
+                    }
+                };
+                if !matches!(value.to_ascii_uppercase().as_str(), "TLS" | "TLS-C" | "SSL") {
+                    reply(&mut stream, 504).await?;
+                    continue;
+                }
+                if session_tls.is_some() {
+                    reply(&mut stream, 503).await?;
+                    continue;
+                }
+                let acceptor = tls::session_acceptor(shared)?;
+                reply_text(&mut stream, 234, "AUTH TLS successful.").await?;
+                // Commands sent before the handshake are dropped with the read buffer.
+                let plain = control.reader.into_inner().unsplit(stream);
+                let secure = plain.start_tls(&acceptor).await?;
+                let (r, w) = tokio::io::split(secure);
+                control = ControlReader::new(r);
+                stream = w;
+                session_tls = Some(acceptor);
+                // RFC4217 4: a new security context needs a new login
+                state = State::AwaitingUser;
+                log.clear_login();
+            },
+            FtpCommand::Pbsz => {
+                // RFC4217 9: the buffer size is 0 for TLS
+                if session_tls.is_none() {
+                    reply(&mut stream, 503).await?;
+                    continue;
+                }
+                pbsz = true;
+                reply_text(&mut stream, 200, "PBSZ=0").await?
+            },
+            FtpCommand::Prot(value) => {
+                if !pbsz {
+                    reply(&mut stream, 503).await?;
+                    continue;
+                }
+                match value.to_ascii_uppercase().as_str() {
+                    "C" if config.require_tls => {
+                        reply_text(&mut stream, 534, "Clear data connections are not allowed.").await?;
+                        continue;
+                    },
+                    "C" => data_protected = false,
+                    "P" => data_protected = true,
+                    "S" | "E" => {
+                        reply(&mut stream, 536).await?;
+                        continue;
+                    },
+                    _ => {
+                        reply(&mut stream, 504).await?;
+                        continue;
+                    }
+                }
+                reply(&mut stream, 200).await?
+            },
+            FtpCommand::Port(dst) => {
+                if epsv_all {
+                    reply(&mut stream, 503).await?;
+                    continue;
+                }
+                // PORT only carries IPv4 addresses
+                if local.is_ipv6() {
+                    reply_unsupported_family(&mut stream, local.ip()).await?;
+                    continue;
+                }
+                if !bounce_safe(dst, peer) {
+                    println!("[{}] Refused data connection to {}", peer, dst);
+                    reply(&mut stream, 504).await?;
+                    continue;
+                }
+                data_conn = DataConn::Active(*dst);
+                reply(&mut stream, 200).await?
+            },
+            FtpCommand::Pasv => {
+                if epsv_all {
+                    reply(&mut stream, 503).await?;
+                    continue;
+                }
+                let listener = match passive_listener(local.ip(), config).await {
+                    Ok(v) => v,
+                    Err(e) => {
                         println!("Error: {:?}", e);
+                        reply(&mut stream, 425).await?;
                         continue;
                     }
-                    stream.write_all(get_reply_message(226)).unwrap();
-                },
-                // "XPWD" => {
-                //     let pwd = fs_path.to_string_lossy().as_bytes().to_vec();
-                //     rt.block_on(handle_data_connection("w", dst_addr.clone(), pwd));
-                //     stream.write_all(get_reply_message(200)).unwrap();
-                //     continue;
-                // },
-                "OPTS" => {
-                    stream.write_all(get_reply_message(504)).unwrap();
+                };
+                let port = listener.local_addr()?.port();
+                // PASV only carries IPv4 addresses
+                let ip = match (config.pasv_address, local.ip()) {
+                    (_, IpAddr::V6(_)) => {
+                        reply_unsupported_family(&mut stream, local.ip()).await?;
+                        continue;
+                    },
+                    (Some(v), _) => v,
+                    (None, IpAddr::V4(v)) => v
+                };
+                data_conn = DataConn::Passive(listener);
+                let [h1, h2, h3, h4] = ip.octets();
+                let text = format!("Entering Passive Mode ({},{},{},{},{},{}).", h1, h2, h3, h4, port >> 8, port & 0xff);
+                reply_text(&mut stream, 227, &text).await?
+            },
+            FtpCommand::Eprt(dst) => {
+                let dst = &canonical(*dst);
+                if epsv_all {
+                    reply(&mut stream, 503).await?;
                     continue;
-                },
-                "QUIT" => {
-                    stream.shutdown(Shutdown::Both).unwrap();
-                    break;
-                },
-                _ => {
-                    stream.write_all(get_reply_message(502)).unwrap();
+                }
+                // Data connections use the family of the control connection.
+                if dst.is_ipv6() != local.is_ipv6() {
+                    reply_unsupported_family(&mut stream, local.ip()).await?;
                     continue;
                 }
+                if !bounce_safe(dst, peer) {
+                    println!("[{}] Refused data connection to {}", peer, dst);
+                    reply(&mut stream, 504).await?;
+                    continue;
+                }
+                data_conn = DataConn::Active(*dst);
+                reply(&mut stream, 200).await?
+            },
+            FtpCommand::Epsv(value) => {
+                match value {
+                    Epsv::All => {
+                        epsv_all = true;
+                        reply(&mut stream, 200).await?;
+                        continue;
+                    },
+                    Epsv::Any => (),
+                    Epsv::Protocol(v) if v == "1" && local.is_ipv4() => (),
+                    Epsv::Protocol(v) if v == "2" && local.is_ipv6() => (),
+                    Epsv::Protocol(_) => {
+                        reply_unsupported_family(&mut stream, local.ip()).await?;
+                        continue;
+                    }
+                }
+                let listener = match passive_listener(local.ip(), config).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 425).await?;
+                        continue;
+                    }
+                };
+                let port = listener.local_addr()?.port();
+                data_conn = DataConn::Passive(listener);
+                reply_text(&mut stream, 229, &format!("Entering Extended Passive Mode (|||{}|).", port)).await?
+            },
+            FtpCommand::Rest(offset) => {
+                state.set_pending(Pending::Restart(*offset));
+                reply_text(&mut stream, 350, &format!("Restarting at {}. Send STORE or RETRIEVE.", offset)).await?
+            },
+            FtpCommand::Retr(_) => {
+                let name = path;
+                if !allows(&state, &name, Permission::Download) {
+                    reply(&mut stream, 550).await?;
+                    continue;
+                }
+                let file = match storage.read(&name, restart.unwrap_or(0)) {
+                    Ok(v) => v,
+                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                        reply(&mut stream, 554).await?;
+                        continue;
+                    },
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?;
+                        continue;
+                    }
+                };
+                reply(&mut stream, 150).await?;
+                let data_stream = match open_data_connection(&mut data_conn, local, peer, data_tls(&session_tls, data_protected), config).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 425).await?;
+                        continue;
+                    }
+                };
+                let encoder = Encoder::new(mode, stru, ascii_type, restart.unwrap_or(0));
+                let (progress, marks) = Progress::new();
+                let transfer = handle_w_data_connection(data_stream, file, encoder, &progress);
+                let xfer = Xfer { path: name.clone(), direction: Direction::Outgoing, ascii: ascii_type };
+                if let Some(size) = supervise_transfer(transfer, &progress, marks, &mut control, &mut stream, &log, Some(xfer)).await? {
+                    fire_hook(config, Notice { size: Some(size), ..notice(HookEvent::Download, state.account(), peer, Some(name)) });
+                }
+            },
+            FtpCommand::List(_) | FtpCommand::Nlst(_) | FtpCommand::Mlsd(_) => {
+                let format = match command {
+                    FtpCommand::List(_) => ListFormat::Long,
+                    FtpCommand::Nlst(_) => ListFormat::Names,
+                    _ => ListFormat::Machine
+                };
+                let name = path;
+                if !allows(&state, &name, Permission::List) {
+                    reply(&mut stream, 550).await?;
+                    continue;
+                }
+                // MLSD only lists directories
+                if format == ListFormat::Machine && !storage.stat(&name).is_ok_and(|v| v.is_dir) {
+                    reply(&mut stream, 501).await?;
+                    continue;
+                }
+                let ls = match list_directory(&storage, &name, format).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?;
+                        continue;
+                    }
+                };
+                reply(&mut stream, 150).await?;
+                let data_stream = match open_data_connection(&mut data_conn, local, peer, data_tls(&session_tls, data_protected), config).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 425).await?;
+                        continue;
+                    }
+                };
+                let encoder = Encoder::new(mode, Structure::File, ascii_type, 0);
+                let (progress, marks) = Progress::new();
+                let transfer = handle_w_data_connection(data_stream, ls.as_slice(), encoder, &progress);
+                supervise_transfer(transfer, &progress, marks, &mut control, &mut stream, &log, None).await?;
+            },
+            FtpCommand::Mlst(_) => {
+                // facts of a single entry on the control connection
+                let name = path;
+                match storage.stat(&name) {
+                    Ok(_) if !allows(&state, &name, Permission::List) => reply(&mut stream, 550).await?,
+                    Ok(entry) => {
+                        let listing = Reply::text(250, &format!("Listing {}", name))
+                            .line(&format_entry(&name, &entry, ListFormat::Machine));
+                        send(&mut stream, listing).await?
+                    },
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?
+                    }
+                }
+            },
+            FtpCommand::Type(ascii) => {
+                ascii_type = *ascii;
+                reply(&mut stream, 200).await?
+            },
+            FtpCommand::Size(_) => {
+                let name = path;
+                let entry = match storage.stat(&name) {
+                    Ok(v) if !v.is_dir && allows(&state, &name, Permission::List) => v,
+                    _ => {
+                        reply(&mut stream, 550).await?;
+                        continue;
+                    }
+                };
+                // In ASCII mode the size is what would go over the wire.
+                let size = if ascii_type {
+                    ascii_size(storage.as_ref(), &name).await
+                } else {
+                    Ok(entry.size)
+                };
+                match size {
+                    Ok(v) => reply_text(&mut stream, 213, &v.to_string()).await?,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?
+                    }
+                }
+            },
+            FtpCommand::Mdtm(_) => {
+                let name = path;
+                match storage.stat(&name) {
+                    Ok(v) if !v.is_dir && allows(&state, &name, Permission::List) => {
+                        let modified: DateTime<Utc> = v.modified.into();
+                        reply_text(&mut stream, 213, &modified.format("%Y%m%d%H%M%S").to_string()).await?
+                    },
+                    _ => reply(&mut stream, 550).await?
+                }
+            },
+            FtpCommand::Mfmt(modified, name) => {
+                let virtual_name = path;
+                if !allows(&state, &virtual_name, Permission::Overwrite) {
+                    reply(&mut stream, 550).await?;
+                    continue;
+                }
+                match storage.set_modified(&virtual_name, SystemTime::from(*modified)) {
+                    Ok(_) => {
+                        let text = format!("Modify={}; {}", modified.format("%Y%m%d%H%M%S"), name);
+                        reply_text(&mut stream, 213, &text).await?
+                    },
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?
+                    }
+                }
+            },
+            FtpCommand::Stor(_) | FtpCommand::Appe(_) => {
+                let name = path;
+                let existing = storage.stat(&name).ok();
+                // changing an existing file also needs the overwrite permission
+                if !allows(&state, &name, Permission::Upload)
+                    || (existing.is_some() && !allows(&state, &name, Permission::Overwrite)) {
+                    reply(&mut stream, 553).await?;
+                    continue;
+                }
+                let write_mode = match (command, restart) {
+                    (FtpCommand::Appe(_), _) => WriteMode::Append,
+                    (_, Some(v)) => WriteMode::Restart(v),
+                    (_, None) => WriteMode::Create
+                };
+                // file position where the upload starts, for restart markers
+                let offset = match write_mode {
+                    WriteMode::Append => existing.map(|v| v.size).unwrap_or(0),
+                    WriteMode::Restart(v) => v,
+                    WriteMode::Create => 0
+                };
+                let file = match storage.write(&name, write_mode) {
+                    Ok(v) => v,
+                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                        reply(&mut stream, 554).await?;
+                        continue;
+                    },
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?;
+                        continue;
+                    }
+                };
+                reply(&mut stream, 150).await?;
+                let data_stream = match open_data_connection(&mut data_conn, local, peer, data_tls(&session_tls, data_protected), config).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 425).await?;
+                        continue;
+                    }
+                };
+                let decoder = Decoder::new(mode, stru, ascii_type);
+                let (progress, marks) = Progress::new();
+                let transfer = handle_r_data_connection(data_stream, file, decoder, &progress, offset);
+                let xfer = Xfer { path: name.clone(), direction: Direction::Incoming, ascii: ascii_type };
+                if let Some(size) = supervise_transfer(transfer, &progress, marks, &mut control, &mut stream, &log, Some(xfer)).await? {
+                    fire_hook(config, Notice { size: Some(size), ..notice(HookEvent::Upload, state.account(), peer, Some(name)) });
+                }
+            },
+            FtpCommand::Stou(_) => {
+                // Store under a name that does not exist yet, based on the argument.
+                let mut name = path.clone();
+                let mut n = 0;
+                while storage.stat(&name).is_ok() {
+                    n += 1;
+                    name = format!("{}.{}", path, n);
+                }
+                if !allows(&state, &name, Permission::Upload) {
+                    reply(&mut stream, 553).await?;
+                    continue;
+                }
+                let file = match storage.write(&name, WriteMode::Create) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?;
+                        continue;
+                    }
+                };
+                let offset = 0;
+                reply_text(&mut stream, 150, &format!("FILE: {}", name)).await?;
+                let data_stream = match open_data_connection(&mut data_conn, local, peer, data_tls(&session_tls, data_protected), config).await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 425).await?;
+                        continue;
+                    }
+                };
+                let decoder = Decoder::new(mode, stru, ascii_type);
+                let (progress, marks) = Progress::new();
+                let transfer = handle_r_data_connection(data_stream, file, decoder, &progress, offset);
+                let xfer = Xfer { path: name.clone(), direction: Direction::Incoming, ascii: ascii_type };
+                if let Some(size) = supervise_transfer(transfer, &progress, marks, &mut control, &mut stream, &log, Some(xfer)).await? {
+                    fire_hook(config, Notice { size: Some(size), ..notice(HookEvent::Upload, state.account(), peer, Some(name)) });
+                }
+            },
+            FtpCommand::Pwd => {
+                reply_text(&mut stream, 257, &format!("{} is the current directory.", quote_path(&cwd))).await?
+            },
+            FtpCommand::Cwd(_) => {
+                let name = path;
+                if storage.stat(&name).is_ok_and(|v| v.is_dir) {
+                    cwd = name;
+                    reply(&mut stream, 250).await?
+                } else {
+                    reply(&mut stream, 550).await?
+                }
+            },
+            FtpCommand::Cdup => {
+                // CDUP in the root stays there
+                cwd = resolve_path(&cwd, "..").unwrap_or_else(|| String::from("/"));
+                reply(&mut stream, 200).await?
+            },
+            FtpCommand::Mkd(_) => {
+                let name = path;
+                if !allows(&state, &name, Permission::Mkdir) {
+                    reply(&mut stream, 550).await?;
+                    continue;
+                }
+                match storage.mkdir(&name) {
+                    Ok(_) => reply_text(&mut stream, 257, &format!("{} created.", quote_path(&name))).await?,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?
+                    }
+                }
+            },
+            FtpCommand::Rmd(_) => {
+                let name = path;
+                // The root and the working directory stay.
+                if name == "/" || name == cwd || !allows(&state, &name, Permission::Delete) {
+                    reply(&mut stream, 550).await?;
+                    continue;
+                }
+                match storage.rmdir(&name) {
+                    Ok(_) => reply(&mut stream, 250).await?,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?
+                    }
+                }
+            },
+            FtpCommand::Dele(_) => {
+                let name = path;
+                if !storage.stat(&name).is_ok_and(|v| !v.is_dir) || !allows(&state, &name, Permission::Delete) {
+                    reply(&mut stream, 550).await?;
+                    continue;
+                }
+                match storage.delete(&name) {
+                    Ok(_) => {
+                        fire_hook(config, notice(HookEvent::Delete, state.account(), peer, Some(name)));
+                        reply(&mut stream, 250).await?
+                    },
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 450).await?
+                    }
+                }
+            },
+            FtpCommand::Rnfr(_) => {
+                let name = path;
+                if name == "/" || storage.stat(&name).is_err() || !allows(&state, &name, Permission::Rename) {
+                    reply(&mut stream, 550).await?;
+                    continue;
+                }
+                state.set_pending(Pending::Rename(name));
+                reply(&mut stream, 350).await?
+            },
+            FtpCommand::Rnto(_) => {
+                let from = match pending_rename {
+                    Some(v) => v,
+                    None => {
+                        reply(&mut stream, 503).await?;
+                        continue;
+                    }
+                };
+                let name = path;
+                if name == "/" || storage.stat(&name).is_ok() || !allows(&state, &name, Permission::Rename) {
+                    reply(&mut stream, 553).await?;
+                    continue;
+                }
+                match storage.rename(&from, &name) {
+                    Ok(_) => {
+                        fire_hook(config, Notice { from: Some(from), ..notice(HookEvent::Rename, state.account(), peer, Some(name)) });
+                        reply(&mut stream, 250).await?
+                    },
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 553).await?
+                    }
+                }
+            },
+            FtpCommand::Mode(value) => {
+                mode = *value;
+                reply(&mut stream, 200).await?
+            },
+            FtpCommand::Stru(value) => {
+                stru = *value;
+                reply(&mut stream, 200).await?
+            },
+            FtpCommand::Noop => reply(&mut stream, 200).await?,
+            FtpCommand::Abor => {
+                // nothing to abort, the data connection is closed anyway
+                data_conn = DataConn::None;
+                reply_text(&mut stream, 225, "No transfer in progress.").await?
+            },
+            FtpCommand::Feat => {
+                // RFC2389
+                let mut features = Reply::text(211, "Features:");
+                for feature in FEATURES {
+                    features = features.line(feature);
+                }
+                if server.tls.is_some() {
+                    features = features.line("AUTH TLS").line("PBSZ").line("PROT");
+                }
+                send(&mut stream, features).await?
+            },
+            FtpCommand::Help(value) => {
+                if value.is_empty() {
+                    let mut help = Reply::text(214, "The following commands are recognized.");
+                    for verbs in command::COMMANDS.chunks(12) {
+                        help = help.line(&verbs.join(" "));
+                    }
+                    send(&mut stream, help.end("Help OK.")).await?;
+                    continue;
+                }
+                let verb = value.trim().to_ascii_uppercase();
+                match command::help(&verb) {
+                    Some(v) => reply_text(&mut stream, 214, &format!("Syntax: {}", v)).await?,
+                    None => reply_text(&mut stream, 502, &format!("Unknown command {}.", verb)).await?
+                }
+            },
+            FtpCommand::Syst => reply_text(&mut stream, 215, "UNIX Type: L8").await?,
+            FtpCommand::Allo => reply_text(&mut stream, 202, "No storage allocation necessary.").await?,
+            FtpCommand::Site(Site::Help) => {
+                let mut help = Reply::text(214, "The following SITE commands are recognized.");
+                for v in command::SITE_COMMANDS {
+                    help = help.line(v);
+                }
+                send(&mut stream, help.end("Help OK.")).await?
+            },
+            FtpCommand::Site(Site::Idle(seconds)) => {
+                match seconds {
+                    None => {
+                        let text = match idle_timeout {
+                            Duration::MAX => String::from("No idle timeout."),
+                            v => format!("Current idle time is {} seconds.", v.as_secs())
+                        };
+                        reply_text(&mut stream, 200, &text).await?
+                    },
+                    Some(v) if SITE_IDLE.contains(v) => {
+                        idle_timeout = Duration::from_secs(*v);
+                        reply_text(&mut stream, 200, &format!("Maximum idle time set to {} seconds.", v)).await?
+                    },
+                    Some(_) => {
+                        let text = format!("Idle time must be between {} and {} seconds.", SITE_IDLE.start(), SITE_IDLE.end());
+                        reply_text(&mut stream, 501, &text).await?
+                    }
+                }
+            },
+            FtpCommand::Site(Site::Chmod(mode, _)) => {
+                let name = path;
+                if !allows(&state, &name, Permission::Overwrite) {
+                    reply(&mut stream, 550).await?;
+                    continue;
+                }
+                match storage.set_mode(&name, *mode) {
+                    Ok(_) => reply_text(&mut stream, 200, "SITE CHMOD command successful.").await?,
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?
+                    }
+                }
+            },
+            FtpCommand::Stat(value) => {
+                if value.is_empty() {
+                    let name = state.account().map(|v| v.name.clone()).unwrap_or_default();
+                    let data_type = if ascii_type { "ASCII" } else { "Image" };
+                    let security = if session_tls.is_some() { "TLS" } else { "none" };
+                    let status = Reply::text(211, "FTP server status:")
+                        .line(&format!("Connected to {}", peer))
+                        .line(&format!("Logged in as {}", name))
+                        .line(&format!("TYPE: {}, STRU: {:?}, MODE: {:?}", data_type, stru, mode))
+                        .line(&format!("Control connection security: {}", security))
+                        .end("End of status");
+                    send(&mut stream, status).await?;
+                    continue;
+                }
+                // RFC959: STAT with a path lists it on the control connection
+                let name = path;
+                if !allows(&state, &name, Permission::List) {
+                    reply(&mut stream, 550).await?;
+                    continue;
+                }
+                match list_directory(&storage, &name, ListFormat::Long).await {
+                    Ok(ls) => {
+                        let mut status = Reply::text(213, &format!("Status of {}:", name));
+                        for line in String::from_utf8_lossy(&ls).lines() {
+                            status = status.line(line);
+                        }
+                        send(&mut stream, status.end("End of status")).await?
+                    },
+                    Err(e) => {
+                        println!("Error: {:?}", e);
+                        reply(&mut stream, 550).await?
+                    }
+                }
+            },
+            FtpCommand::Opts(value) => {
+                // RFC2640: pathnames are always UTF-8, so UTF8 ON only needs an answer.
+                let value = value.to_ascii_uppercase();
+                match value.split_whitespace().collect::<Vec<&str>>()[..] {
+                    ["UTF8", "ON"] | ["UTF8"] => reply_text(&mut stream, 200, "UTF8 set to on.").await?,
+                    _ => reply_text(&mut stream, 501, "Option not understood.").await?
+                }
+            },
+            FtpCommand::Quit => {
+                reply(&mut stream, 221).await?;
+                stream.shutdown().await?;
+                break;
+            },
+            _ => {
+                reply(&mut stream, 502).await?;
+                continue;
             }
         }
     }
+    Ok(())
 }
 
-async fn handle_r_data_connection(dst_addr: String, path: PathBuf) -> Result<(), std::io::Error> {
-    let src = "127.0.0.1:20".parse().unwrap();
-    let dst = dst_addr.parse().unwrap();
-    let socket = TcpSocket::new_v4().unwrap();
-    socket.bind(src).unwrap();
-    let stream: TokioTcpStream = socket.connect(dst).await.unwrap();
+/// Hook notice of the logged in account
+fn notice(event: HookEvent, account: Option<&Account>, peer: SocketAddr, path: Option<String>) -> Notice {
+    let user = account.map(|v| v.name.clone()).unwrap_or_default();
+    Notice { event, user, client: peer.ip(), path, size: None, from: None }
+}
 
-    loop {
-        let ready = stream.ready(Interest::READABLE).await.unwrap();
+fn fire_hook(config: &Config, notice: Notice) {
+    hook::fire(&config.hooks, Duration::from_secs(config.hook_timeout), notice);
+}
 
-        if ready.is_readable() {
-            let mut data = vec![0; 1460];
-            match stream.try_read(&mut data) {
-                Ok(n) => {
-                    let read_buf = &mut data[..n];
-                    let mut file = File::create(path)?;
-                    file.write_all(read_buf)?;
-                    return Ok(())
-                },
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    continue;
-                },
-                Err(e) => {
-                    return Err(e)
-                }
-            }
+/// Check a permission of the logged in account on a path as the client sees it.
+fn allows(state: &State, name: &str, permission: Permission) -> bool {
+    state.account().is_some_and(|v| v.permissions.allows(name, permission))
+}
+
+async fn send<S: AsyncWrite + Unpin>(stream: &mut S, reply: Reply) -> io::Result<()> {
+    stream.write_all(&reply.to_bytes()).await
+}
+
+async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, code: i32) -> io::Result<()> {
+    send(stream, Reply::new(code)).await
+}
+
+// 226 on a complete transfer, 426/451 otherwise
+async fn reply_transfer<S: AsyncWrite + Unpin>(stream: &mut S, result: Result<u64, TransferError>) -> io::Result<()> {
+    match result {
+        Ok(_) => reply(stream, 226).await,
+        Err(TransferError::Connection(e)) => {
+            println!("Data connection error: {:?}", e);
+            reply(stream, 426).await
+        },
+        Err(TransferError::Local(e)) => {
+            println!("File error: {:?}", e);
+            reply(stream, 451).await
         }
     }
 }
 
-async fn handle_w_data_connection(dst_addr: String, data: Vec<u8>) {
-    let src = "127.0.0.1:20".parse().unwrap();
-    let dst = dst_addr.parse().unwrap();
-    let socket = TcpSocket::new_v4().unwrap();
-    socket.bind(src).unwrap();
-    let stream: TokioTcpStream = socket.connect(dst).await.unwrap();
+async fn reply_text<S: AsyncWrite + Unpin>(stream: &mut S, code: i32, text: &str) -> io::Result<()> {
+    send(stream, Reply::text(code, text)).await
+}
 
+/// Resolve a client path against the working directory.
+/// Returns the path as the client sees it, or None when `..` climbs above the session root.
+fn resolve_path(cwd: &str, value: &str) -> Option<String> {
+    let mut names: Vec<String> = Vec::new();
+    let joined = if value.starts_with('/') {
+        PathBuf::from(value)
+    } else {
+        Path::new(cwd).join(value)
+    };
+    for component in joined.components() {
+        match component {
+            Component::Normal(v) => names.push(v.to_string_lossy().into_owned()),
+            Component::ParentDir => { names.pop()?; },
+            _ => ()
+        }
+    }
+    Some(format!("/{}", names.join("/")))
+}
+
+// File size after LF -> CRLF conversion
+async fn ascii_size(storage: &dyn Storage, path: &str) -> io::Result<u64> {
+    let mut file = storage.read(path, 0)?;
+    let mut buf = vec![0u8; 8192];
+    let mut size = 0u64;
+    let mut last = 0u8;
     loop {
-        let ready = stream.ready(Interest::WRITABLE).await.unwrap();
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        for &b in &buf[..n] {
+            if b == 10u8 && last != 13u8 {
+                size += 1;
+            }
+            size += 1;
+            last = b;
+        }
+    }
+    Ok(size)
+}
 
-        if ready.is_writable() {
-            match stream.try_write(&data) {
-                Ok(_) => {
-                    break;
+// RFC959 appendix II: embedded double quotes are doubled
+fn quote_path(path: &str) -> String {
+    format!("\"{}\"", path.replace('"', "\"\""))
+}
+
+// RFC2428: 522 tells the client which network protocol to use
+async fn reply_unsupported_family<S: AsyncWrite + Unpin>(stream: &mut S, ip: IpAddr) -> io::Result<()> {
+    reply_text(stream, 522, &format!("Network protocol not supported, use ({}).", net_prt(ip))).await
+}
+
+// RFC2428 network protocol number
+fn net_prt(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 1,
+        IpAddr::V6(_) => 2
+    }
+}
+
+
+/// Bind a listener for PASV/EPSV within the configured port range.
+async fn passive_listener(ip: IpAddr, config: &Config) -> io::Result<TcpListener> {
+    if config.pasv_min_port == 0 {
+        return TcpListener::bind(SocketAddr::new(ip, 0)).await
+    }
+    let max_port = config.pasv_max_port.max(config.pasv_min_port);
+    for port in config.pasv_min_port..=max_port {
+        if let Ok(v) = TcpListener::bind(SocketAddr::new(ip, port)).await {
+            return Ok(v)
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, "No free port in the passive range."))
+}
+
+/// An IPv4-mapped IPv6 address as the IPv4 address it is
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// RFC2577: active data connections only go to the client, the server must not be a relay.
+fn bounce_safe(dst: &SocketAddr, peer: SocketAddr) -> bool {
+    dst.ip().to_canonical() == peer.ip().to_canonical()
+}
+
+/// TLS for the next data connection after PROT P
+fn data_tls(tls: &Option<TlsAcceptor>, data_protected: bool) -> Option<&TlsAcceptor> {
+    tls.as_ref().filter(|_| data_protected)
+}
+
+/// Establish the data connection prepared by PORT or PASV/EPSV, within the data timeout.
+/// The server runs the TLS handshake in both directions (RFC4217 7).
+async fn open_data_connection(data_conn: &mut DataConn, local: SocketAddr, peer: SocketAddr,
+        tls: Option<&TlsAcceptor>, config: &Config) -> io::Result<FtpStream> {
+    let connect = async {
+        let stream = FtpStream::Plain(connect_data(data_conn, local, peer, config.active_port).await?);
+        match tls {
+            Some(acceptor) => stream.start_tls(acceptor).await,
+            None => Ok(stream)
+        }
+    };
+    let stream = match timeout(Duration::from_secs(config.data_timeout), connect).await {
+        Ok(v) => v?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "Data connection timed out."))
+    };
+    if tls.is_some() && config.require_tls_reuse && !stream.is_resumed() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+            "Data connection did not resume the control connection's TLS session."))
+    }
+    Ok(stream)
+}
+
+async fn connect_data(data_conn: &mut DataConn, local: SocketAddr, peer: SocketAddr, active_port: u16) -> io::Result<TokioTcpStream> {
+    match std::mem::replace(data_conn, DataConn::None) {
+        DataConn::Active(dst) => {
+            // connect from the control connection's address
+            let src = SocketAddr::new(local.ip(), active_port);
+            let socket = match src {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?
+            };
+            // a fixed port is still in TIME_WAIT from the previous transfer
+            if active_port != 0 {
+                socket.set_reuseaddr(true)?;
+            }
+            socket.bind(src)?;
+            socket.connect(dst).await
+        },
+        DataConn::Passive(listener) => {
+            let (stream, addr) = listener.accept().await?;
+            // Only the client on the control connection may connect.
+            if addr.ip() != peer.ip() {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                    format!("Data connection from unexpected address {}", addr)))
+            }
+            Ok(stream)
+        },
+        DataConn::None => {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "No PORT or PASV before transfer."))
+        }
+    }
+}
+
+/// Wait for a transfer while the control connection stays readable.
+/// ABOR cancels the transfer, STAT reports its progress and NOOP is answered,
+/// other commands are queued until the transfer is over (RFC959 4.1.3).
+/// File transfers end up in the xferlog, complete or not.
+/// Returns the size of a complete transfer.
+async fn supervise_transfer<F, S>(transfer: F, progress: &Progress, mut marks: mpsc::UnboundedReceiver<Reply>,
+        control: &mut ControlReader<S>, stream: &mut ControlStream<S>, log: &SessionLog, xfer: Option<Xfer>) -> io::Result<Option<u64>>
+        where F: Future<Output = Result<u64, TransferError>>, S: AsyncRead + AsyncWrite + Unpin {
+    tokio::pin!(transfer);
+    let started = Instant::now();
+    // total of a complete transfer, None when it failed
+    let finished = |total: Option<u64>| {
+        if let Some(v) = &xfer {
+            let bytes = total.unwrap_or_else(|| progress.bytes.load(Ordering::Relaxed));
+            log.transfer(v, bytes, started.elapsed(), total.is_some());
+        }
+    };
+    let mut control_open = true;
+    loop {
+        tokio::select! {
+            // restart markers and the final reply keep their order
+            biased;
+            Some(mark) = marks.recv() => send(stream, mark).await?,
+            result = &mut transfer => {
+                while let Ok(mark) = marks.try_recv() {
+                    send(stream, mark).await?;
                 }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    continue
+                let total = result.as_ref().ok().copied();
+                finished(total);
+                reply_transfer(stream, result).await?;
+                return Ok(total)
+            },
+            line = control.read_line(), if control_open => {
+                let line = match line? {
+                    Some(v) => v,
+                    // finish the transfer, the session ends afterwards
+                    None => {
+                        control_open = false;
+                        continue;
+                    }
+                };
+                // queued commands are logged when they are processed
+                let command = command::parse(&line);
+                if matches!(command, Ok(FtpCommand::Abor | FtpCommand::Noop)) || matches!(&command, Ok(FtpCommand::Stat(v)) if v.is_empty()) {
+                    log.command(&line);
                 }
-                Err(e) => {
-                    println!("Error: {:?}", e);
-                    break;
+                match command {
+                    Ok(FtpCommand::Abor) => {
+                        // dropping the transfer closes the data connection
+                        finished(None);
+                        reply_text(stream, 426, "Transfer aborted.").await?;
+                        reply_text(stream, 226, "Abort successful.").await?;
+                        return Ok(None)
+                    },
+                    Ok(FtpCommand::Stat(v)) if v.is_empty() => {
+                        let text = format!("Transfer in progress, {} bytes transferred.", progress.bytes.load(Ordering::Relaxed));
+                        reply_text(stream, 213, &text).await?
+                    },
+                    Ok(FtpCommand::Noop) => reply(stream, 200).await?,
+                    _ => control.queued.push_back(line)
                 }
             }
         }
     }
 }
 
-fn control_filesystem(method: &str, path: PathBuf) -> Result<Vec<u8>, std::io::Error> {
-    match method {
-        "ls" => {
-            let mut buf = Vec::new();
-            let mut crlf = vec![13u8, 10u8];
-            let entries = std::fs::read_dir(path).unwrap();
-            for elm in entries {
-                let file = elm.unwrap().file_name();
-                let mut buff = file.into_encoded_bytes();
-                buf.append(&mut buff);
-                buf.append(&mut crlf);
-            }
-            Ok(buf)
-        },
-        "read" => {
-            let mut file = File::open(path)?;
-            let mut buf = Vec::new();
-            file.read_to_end(&mut buf)?;
-            Ok(buf)
+/// Receive from the data connection until the client closes it.
+/// Data is written as it arrives, so an interrupted upload leaves a partial file.
+/// Restart markers in block and compressed mode are answered with 110 on the control connection.
+async fn handle_r_data_connection<W: AsyncWrite + Unpin>(mut stream: FtpStream, mut sink: W, mut decoder: Decoder,
+        progress: &Progress, offset: u64) -> Result<u64, TransferError> {
+    let mut buf = vec![0u8; DATA_BUF_SIZE];
+    let mut out = Vec::with_capacity(DATA_BUF_SIZE);
+    let mut total = 0u64;
+    loop {
+        let n = stream.read(&mut buf).await.map_err(TransferError::Connection)?;
+        // end of data
+        if n == 0 {
+            // answer the client's TLS close_notify, the data itself is already complete
+            let _ = stream.shutdown().await;
+            break;
+        }
+        out.clear();
+        let markers = decoder.decode(&buf[..n], &mut out);
+        let mut written = 0;
+        for (marker, pos) in markers {
+            // everything before the marker has to be on disk first
+            sink.write_all(&out[written..pos]).await.map_err(TransferError::Local)?;
+            sink.flush().await.map_err(TransferError::Local)?;
+            written = pos;
+            let position = offset + total + pos as u64;
+            let _ = progress.marks.send(Reply::text(110, &format!("MARK {} = {}", marker, position)));
+        }
+        sink.write_all(&out[written..]).await.map_err(TransferError::Local)?;
+        total += out.len() as u64;
+        progress.bytes.store(total, Ordering::Relaxed);
+    }
+    out.clear();
+    let complete = decoder.finish(&mut out);
+    sink.write_all(&out).await.map_err(TransferError::Local)?;
+    total += out.len() as u64;
+    sink.flush().await.map_err(TransferError::Local)?;
+    if !complete {
+        return Err(TransferError::Connection(io::Error::new(io::ErrorKind::UnexpectedEof,
+            "Data connection closed before the end of file.")))
+    }
+    Ok(total)
+}
+
+/// Send everything from the source, then close the data connection to mark the end.
+async fn handle_w_data_connection<R: AsyncRead + Unpin>(mut stream: FtpStream, mut source: R, mut encoder: Encoder,
+        progress: &Progress) -> Result<u64, TransferError> {
+    let mut buf = vec![0u8; DATA_BUF_SIZE];
+    let mut total = 0u64;
+    loop {
+        let n = source.read(&mut buf).await.map_err(TransferError::Local)?;
+        if n == 0 {
+            break;
+        }
+        let data = encoder.encode(&buf[..n]);
+        stream.write_all(&data).await.map_err(TransferError::Connection)?;
+        total += n as u64;
+        progress.bytes.store(total, Ordering::Relaxed);
+    }
+    stream.write_all(&encoder.finish()).await.map_err(TransferError::Connection)?;
+    stream.shutdown().await.map_err(TransferError::Connection)?;
+    Ok(total)
+}
+
+/// List a directory, or a single file, one CRLF terminated line per entry.
+/// Runs on the blocking pool, a large directory on disk takes a while.
+async fn list_directory(storage: &Arc<dyn Storage>, path: &str, format: ListFormat) -> io::Result<Vec<u8>> {
+    let (storage, path) = (storage.clone(), path.to_string());
+    tokio::task::spawn_blocking(move || list_entries(storage.as_ref(), &path, format)).await?
+}
+
+fn list_entries(storage: &dyn Storage, path: &str, format: ListFormat) -> io::Result<Vec<u8>> {
+    let entry = storage.stat(path)?;
+    let mut entries = Vec::new();
+    if entry.is_dir {
+        entries = storage.list(path)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        if format == ListFormat::Machine {
+            entries.insert(0, Entry { name: ".".to_string(), ..entry });
+        }
+    } else {
+        entries.push(entry);
+    }
+
+    let mut buf = Vec::new();
+    for entry in entries.iter() {
+        buf.extend(format_entry(&entry.name, entry, format).as_bytes());
+        buf.extend(b"\r\n");
+    }
+    Ok(buf)
+}
+
+fn format_entry(name: &str, entry: &Entry, format: ListFormat) -> String {
+    let modified: DateTime<Utc> = entry.modified.into();
+    match format {
+        ListFormat::Names => name.to_string(),
+        ListFormat::Long => {
+            // "Mon DD HH:MM" within half a year, "Mon DD  YYYY" otherwise
+            let local: DateTime<Local> = modified.into();
+            let age = Local::now().signed_duration_since(local);
+            let date = if age.num_days() < 180 && age.num_days() > -180 {
+                local.format("%b %e %H:%M")
+            } else {
+                local.format("%b %e  %Y")
+            };
+            let links = if entry.is_dir { 2 } else { 1 };
+            format!("{} {:>3} ftp      ftp      {:>12} {} {}",
+                permission_string(entry), links, entry.size, date, name)
         },
-        _ => {
-            panic!("oh...")
-        }
-    }
-
-}
-
-fn get_reply_message(code: i32) -> &'static [u8] {
-    let message = match code {
-        110 => "110 Restart marker reply.\r\n",
-        120 => "120 Service ready in 86400 minutes.\r\n",
-        125 => "125 Data connection already open; transfer starting.\r\n",
-        150 => "150 File status okay; about to open data connection.\r\n",
-        200 => "200 Command okay.\r\n",
-        202 => "202 Command not implemented, superfluous at this site.\r\n",
-        211 => "211 System status, or system help reply.\r\n",
-        212 => "212 Directory status.\r\n",
-        213 => "213 File status.\r\n",
-        214 => "214 Help message.\r\n",
-        215 => "215 NAME system type.\r\n",
-        220 => "220 Service ready for new user.\r\n",
-        221 => "221 Service closing control connection.\r\n",
-        225 => "225 Data connection open; no transfer in progress.\r\n",
-        226 => "226 Closing data connection.\r\n",
-        227 => "227 Entering Passive Mode (h1,h2,h3,h4,p1,p2).\r\n",
-        230 => "230 User logged in, proceed.\r\n",
-        250 => "250 Requested file action okay, completed.\r\n",
-        257 => "257 \"PATHNAME\" created.\r\n",
-        331 => "331 User name okay, need password.\r\n",
-        332 => "332 Need account for login.\r\n",
-        350 => "350 Requested file action pending further information.\r\n",
-        421 => "421 Service not available, closing control connection.\r\n",
-        425 => "425 Can't open data connection.\r\n",
-        426 => "426 Connection closed; transfer aborted.\r\n",
-        450 => "450 Requested file action not taken.\r\n",
-        451 => "451 Requested action aborted: local error in processing.\r\n",
-        452 => "452 Requested action not taken.\r\n",
-        500 => "500 Syntax error, command unrecognized.\r\n",
-        501 => "501 Syntax error in parameters or arguments.\r\n",
-        502 => "502 Command not implemented.\r\n",
-        503 => "503 Bad sequence of commands.\r\n",
-        504 => "504 Command not implemented for that parameter.\r\n",
-        530 => "530 Not logged in.\r\n",
-        532 => "532 Need account for storing files.\r\n",
-        550 => "550 Requested action not taken.\r\n",
-        551 => "551 Requested action aborted: page type unknown.\r\n",
-        552 => "552 Requested file action aborted.\r\n",
-        553 => "553 Requested action not taken.\r\n",
-        _ => panic!("oh...")
-    };
+        ListFormat::Machine => {
+            // RFC3659 7.5: type, size, modify, perm, unique
+            let kind = match (name, entry.is_dir) {
+                (".", _) => "cdir",
+                (_, true) => "dir",
+                _ => "file"
+            };
+            let writable = entry.mode & 0o200 != 0;
+            let perm = match (entry.is_dir, writable) {
+                (true, true) => "cdeflmp",
+                (true, false) => "el",
+                (false, true) => "adfrw",
+                (false, false) => "r"
+            };
+            let mut facts = format!("type={};", kind);
+            if !entry.is_dir {
+                facts.push_str(&format!("size={};", entry.size));
+            }
+            facts.push_str(&format!("modify={};perm={};", modified.format("%Y%m%d%H%M%S"), perm));
+            if let Some(v) = &entry.unique {
+                facts.push_str(&format!("unique={};", v));
+            }
+            format!("{} {}", facts, name)
+        }
+    }
+}
+
+// "drwxr-xr-x" style mode string
+fn permission_string(entry: &Entry) -> String {
+    let mut perm = String::from(if entry.is_dir { "d" } else { "-" });
+    for shift in [6, 3, 0] {
+        let bits = (entry.mode >> shift) & 7;
+        perm.push(if bits & 4 != 0 { 'r' } else { '-' });
+        perm.push(if bits & 2 != 0 { 'w' } else { '-' });
+        perm.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    perm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use tokio::io::DuplexStream;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        config: Config,
+    }
+
+    // anonymous sessions may do everything except below /readonly
+    const USERS: &str = r#"
+[anonymous]
+permissions = ["list", "download", "upload", "overwrite", "delete", "mkdir", "rename"]
+
+[[anonymous.rule]]
+path = "/readonly"
+permissions = ["list", "download"]
+"#;
+
+    async fn server(name: &str) -> Arc<Server> {
+        let dir = std::env::temp_dir().join(format!("ntk-rfc-ftpd-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("ftp-users.toml"), USERS).unwrap();
+
+        let root = dir.join("ftp-root");
+        let users = FileAuthenticator::load(&dir.join("ftp-users.toml"), &root).unwrap();
+        let permissions = users.anonymous_permissions();
+        let memory = MemoryStorage::new();
+        let home = memory.home("/pub").unwrap();
+        home.mkdir("/readonly").unwrap();
+        home.write("/readonly/a.txt", WriteMode::Create).unwrap().write_all(b"fixed").await.unwrap();
+
+        let config = Cli::parse_from(["ftpd", "--login-delay", "0"]).config;
+        Arc::new(Server {
+            config,
+            authenticator: Arc::new(AnonymousAuthenticator::new(Box::new(users), root.join("pub"), permissions)),
+            backend: Backend::Memory(root, memory),
+            logs: Arc::new(Logs::open(&dir.join("xferlog"), &dir.join("ftp-audit.log")).unwrap()),
+            tls: None,
+        })
+    }
+
+    /// Client side of a session over an in-memory control connection
+    struct Session {
+        reader: BufReader<ReadHalf<DuplexStream>>,
+        writer: WriteHalf<DuplexStream>,
+    }
+
+    impl Session {
+        async fn start(server: Arc<Server>) -> Session {
+            let (client, stream) = tokio::io::duplex(64 * 1024);
+            let limiter = Arc::new(Limiter::new(0, 0, 0, Duration::ZERO));
+            let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+            let local: SocketAddr = "127.0.0.1:21".parse().unwrap();
+            let slot = limiter.connect(peer).unwrap();
+            tokio::spawn(handle_control_connection(stream, local, slot, server));
+            let (reader, writer) = tokio::io::split(client);
+            let mut session = Session { reader: BufReader::new(reader), writer };
+            assert!(session.reply().await.starts_with("220"));
+            session
+        }
+
+        async fn login(server: Arc<Server>) -> Session {
+            let mut session = Session::start(server).await;
+            assert_eq!(session.code("USER anonymous").await, 331);
+            assert_eq!(session.code("PASS guest@example.com").await, 230);
+            assert_eq!(session.code("TYPE I").await, 200);
+            session
+        }
+
+        // last line of the next reply
+        async fn reply(&mut self) -> String {
+            loop {
+                let mut line = String::new();
+                self.reader.read_line(&mut line).await.unwrap();
+                assert!(line.len() >= 4, "short reply {:?}", line);
+                if line.as_bytes()[3] == b' ' {
+                    return line.trim_end().to_string()
+                }
+            }
+        }
+
+        async fn command(&mut self, line: &str) -> String {
+            self.writer.write_all(format!("{}\r\n", line).as_bytes()).await.unwrap();
+            self.reply().await
+        }
+
+        async fn code(&mut self, line: &str) -> i32 {
+            self.command(line).await[..3].parse().unwrap()
+        }
+
+        // EPSV, returns the port
+        async fn passive(&mut self) -> u16 {
+            let text = self.command("EPSV").await;
+            assert!(text.starts_with("229"), "{}", text);
+            text.split('|').nth(3).unwrap().parse().unwrap()
+        }
+
+        // the transfer command after EPSV, the reply code when it does not start
+        async fn data_connection(&mut self, port: u16, line: &str) -> Result<TokioTcpStream, i32> {
+            match self.code(line).await {
+                150 => Ok(TokioTcpStream::connect(("127.0.0.1", port)).await.unwrap()),
+                code => Err(code)
+            }
+        }
+
+        async fn upload(&mut self, line: &str, data: &[u8]) -> i32 {
+            let port = self.passive().await;
+            self.upload_to(port, line, data).await
+        }
+
+        async fn upload_to(&mut self, port: u16, line: &str, data: &[u8]) -> i32 {
+            let mut stream = match self.data_connection(port, line).await {
+                Ok(v) => v,
+                Err(code) => return code
+            };
+            stream.write_all(data).await.unwrap();
+            stream.shutdown().await.unwrap();
+            drop(stream);
+            self.reply().await[..3].parse().unwrap()
+        }
+
+        async fn download(&mut self, line: &str) -> Vec<u8> {
+            let port = self.passive().await;
+            self.download_from(port, line).await
+        }
+
+        async fn download_from(&mut self, port: u16, line: &str) -> Vec<u8> {
+            let mut stream = self.data_connection(port, line).await.unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await.unwrap();
+            assert!(self.reply().await.starts_with("226"));
+            data
+        }
+    }
+
+    #[tokio::test]
+    async fn login_gate() {
+        let mut session = Session::start(server("gate").await).await;
+        assert_eq!(session.code("PWD").await, 530);
+        assert_eq!(session.code("RETR /readonly/a.txt").await, 530);
+        assert_eq!(session.code("FEAT").await, 211);
+        assert_eq!(session.code("USER anonymous").await, 331);
+        assert_eq!(session.code("PASS nomail").await, 530);
+        assert_eq!(session.code("PWD").await, 530);
+        assert_eq!(session.code("USER anonymous").await, 331);
+        assert_eq!(session.code("PASS guest@example.com").await, 230);
+        assert_eq!(session.command("PWD").await, "257 \"/\" is the current directory.");
+    }
+
+    #[tokio::test]
+    async fn sequencing() {
+        let mut session = Session::start(server("sequence").await).await;
+        // PASS needs USER right before it
+        assert_eq!(session.code("PASS guest@example.com").await, 503);
+        let mut session = Session::login(server("sequence2").await).await;
+        assert_eq!(session.code("RNTO b.txt").await, 503);
+        // RNTO has to follow RNFR directly
+        assert_eq!(session.code("RNFR /readonly").await, 550);
+        assert_eq!(session.upload("STOR a.txt", b"data").await, 226);
+        assert_eq!(session.code("RNFR a.txt").await, 350);
+        assert_eq!(session.code("NOOP").await, 200);
+        assert_eq!(session.code("RNTO b.txt").await, 503);
+        // REST only applies to the next command
+        let port = session.passive().await;
+        assert_eq!(session.code("REST 2").await, 350);
+        assert_eq!(session.code("NOOP").await, 200);
+        assert_eq!(session.download_from(port, "RETR a.txt").await, b"data");
+    }
+
+    #[tokio::test]
+    async fn rename() {
+        let mut session = Session::login(server("rename").await).await;
+        assert_eq!(session.upload("STOR a.txt", b"one").await, 226);
+        assert_eq!(session.upload("STOR b.txt", b"two").await, 226);
+        assert_eq!(session.code("RNFR missing.txt").await, 550);
+        // the target exists
+        assert_eq!(session.code("RNFR a.txt").await, 350);
+        assert_eq!(session.code("RNTO b.txt").await, 553);
+        assert_eq!(session.code("RNFR a.txt").await, 350);
+        assert_eq!(session.code("RNTO c.txt").await, 250);
+        assert_eq!(session.code("SIZE a.txt").await, 550);
+        assert_eq!(session.download("RETR c.txt").await, b"one");
+    }
 
-    message.as_bytes()
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn restart_upload() {
+        let mut session = Session::login(server("restart").await).await;
+        assert_eq!(session.upload("STOR a.txt", b"hello, moon").await, 226);
+        let port = session.passive().await;
+        assert_eq!(session.code("REST 7").await, 350);
+        assert_eq!(session.upload_to(port, "STOR a.txt", b"world").await, 226);
+        assert_eq!(session.download("RETR a.txt").await, b"hello, world");
+        // beyond the end of the file
+        let port = session.passive().await;
+        assert_eq!(session.code("REST 100").await, 350);
+        assert_eq!(session.upload_to(port, "STOR a.txt", b"x").await, 554);
+        let port = session.passive().await;
+        assert_eq!(session.code("REST 7").await, 350);
+        assert_eq!(session.download_from(port, "RETR a.txt").await, b"world");
+        // REST must come right before the transfer
+        assert_eq!(session.code("REST 7").await, 350);
+        assert_eq!(session.upload("STOR a.txt", b"new").await, 226);
+        assert_eq!(session.download("RETR a.txt").await, b"new");
+    }
+
+    #[tokio::test]
+    async fn permissions() {
+        let mut session = Session::login(server("permissions").await).await;
+        assert_eq!(session.download("RETR /readonly/a.txt").await, b"fixed");
+        assert_eq!(session.upload("STOR /readonly/b.txt", b"data").await, 553);
+        assert_eq!(session.code("STOR /readonly/a.txt").await, 553);
+        assert_eq!(session.code("APPE /readonly/a.txt").await, 553);
+        assert_eq!(session.code("DELE /readonly/a.txt").await, 550);
+        assert_eq!(session.code("MKD /readonly/dir").await, 550);
+        assert_eq!(session.code("RNFR /readonly/a.txt").await, 550);
+        assert_eq!(session.upload("STOR a.txt", b"data").await, 226);
+        assert_eq!(session.code("RNFR a.txt").await, 350);
+        assert_eq!(session.code("RNTO /readonly/b.txt").await, 553);
+        // relative to the working directory
+        assert_eq!(session.code("CWD readonly").await, 250);
+        assert_eq!(session.code("DELE a.txt").await, 550);
+        assert_eq!(session.code("DELE ../a.txt").await, 250);
+    }
+
+    #[tokio::test]
+    async fn path_escape() {
+        let mut session = Session::login(server("escape").await).await;
+        assert_eq!(session.code("CWD ..").await, 550);
+        assert_eq!(session.code("RETR ../../etc/passwd").await, 550);
+        assert_eq!(session.code("SIZE /readonly/../../pub/readonly/a.txt").await, 550);
+        assert_eq!(session.code("MKD dir/../../up").await, 550);
+        assert_eq!(session.code("CDUP").await, 200);
+        assert_eq!(session.command("PWD").await, "257 \"/\" is the current directory.");
+        assert_eq!(session.code("SIZE readonly/../readonly/a.txt").await, 213);
+    }
+}
@@ -0,0 +1,193 @@
+//! Hooks run on session events, a local command or a JSON POST to a local HTTP endpoint.
+
+use std::net::IpAddr;
+use std::io;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+/// Events a hook can be attached to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookEvent {
+    // upload complete
+    Upload,
+    // download complete
+    Download,
+    Delete,
+    Rename,
+    Login,
+}
+
+impl HookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Upload => "upload",
+            HookEvent::Download => "download",
+            HookEvent::Delete => "delete",
+            HookEvent::Rename => "rename",
+            HookEvent::Login => "login",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+    // run through the shell
+    Command(String),
+    // http://host:port/path
+    Post { authority: String, path: String },
+}
+
+/// `<event>=<command>` or `<event>=http://<host>[:<port>]/<path>`
+#[derive(Debug, Clone)]
+pub struct Hook {
+    event: HookEvent,
+    action: Action,
+}
+
+impl FromStr for Hook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (event, action) = s.split_once('=').ok_or("expected <event>=<command or url>")?;
+        let event = match event.to_ascii_lowercase().as_str() {
+            "upload" => HookEvent::Upload,
+            "download" => HookEvent::Download,
+            "delete" => HookEvent::Delete,
+            "rename" => HookEvent::Rename,
+            "login" => HookEvent::Login,
+            _ => return Err(format!("unknown event {:?}, expected upload, download, delete, rename or login", event))
+        };
+        let action = match action.strip_prefix("http://") {
+            Some(v) => {
+                let (authority, path) = match v.find('/') {
+                    Some(i) => (&v[..i], &v[i..]),
+                    None => (v, "/")
+                };
+                if authority.is_empty() {
+                    return Err(format!("no host in {:?}", action));
+                }
+                // port 80 when none is given
+                let authority = match authority.rsplit_once(':') {
+                    Some((_, port)) if !port.contains(']') => authority.to_string(),
+                    _ => format!("{}:80", authority)
+                };
+                Action::Post { authority, path: path.to_string() }
+            },
+            None if action.starts_with("https://") => return Err("only plain http endpoints are supported".to_string()),
+            None if action.trim().is_empty() => return Err("empty command".to_string()),
+            None => Action::Command(action.to_string())
+        };
+        Ok(Hook { event, action })
+    }
+}
+
+/// What happened, the JSON body of a POST
+#[derive(Debug, Serialize)]
+pub struct Notice {
+    pub event: HookEvent,
+    pub user: String,
+    pub client: IpAddr,
+    // path as the client sees it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    // bytes transferred
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    // old path of a rename
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+}
+
+/// Start the hooks of the event in the background, each one is stopped after `limit`.
+pub fn fire(hooks: &[Hook], limit: Duration, notice: Notice) {
+    let body = match serde_json::to_vec(&notice) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Hook error: {:?}", e);
+            return;
+        }
+    };
+    let env = environment(&notice);
+    for hook in hooks.iter().filter(|v| v.event == notice.event) {
+        let action = hook.action.clone();
+        let (body, env) = (body.clone(), env.clone());
+        tokio::spawn(async move {
+            let result = match &action {
+                Action::Command(v) => timeout(limit, run(v, env)).await,
+                Action::Post { authority, path } => timeout(limit, post(authority, path, &body)).await
+            };
+            match result {
+                Ok(Ok(_)) => {},
+                Ok(Err(e)) => println!("Hook {:?} failed: {:?}", action, e),
+                Err(_) => println!("Hook {:?} timed out", action)
+            }
+        });
+    }
+}
+
+// FTP_EVENT, FTP_USER, FTP_CLIENT_IP, FTP_PATH, FTP_SIZE, FTP_FROM
+fn environment(notice: &Notice) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("FTP_EVENT", notice.event.name().to_string()),
+        ("FTP_USER", notice.user.clone()),
+        ("FTP_CLIENT_IP", notice.client.to_string()),
+    ];
+    if let Some(v) = &notice.path {
+        env.push(("FTP_PATH", v.clone()));
+    }
+    if let Some(v) = notice.size {
+        env.push(("FTP_SIZE", v.to_string()));
+    }
+    if let Some(v) = &notice.from {
+        env.push(("FTP_FROM", v.clone()));
+    }
+    env
+}
+
+async fn run(command: &str, env: Vec<(&'static str, String)>) -> io::Result<()> {
+    #[cfg(windows)]
+    let mut command = {
+        let mut v = Command::new("cmd");
+        v.arg("/C").arg(command);
+        v
+    };
+    #[cfg(not(windows))]
+    let mut command = {
+        let mut v = Command::new("sh");
+        v.arg("-c").arg(command);
+        v
+    };
+    // the shell is killed when the timeout drops it
+    let status = command.envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .status().await?;
+    if !status.success() {
+        return Err(io::Error::other(format!("command exited with {}", status)));
+    }
+    Ok(())
+}
+
+async fn post(authority: &str, path: &str, body: &[u8]) -> io::Result<()> {
+    let mut stream = TcpStream::connect(authority).await?;
+    let head = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path, authority, body.len());
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+
+    // "HTTP/1.1 200 OK"
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).await?;
+    match status.split(' ').nth(1) {
+        Some(v) if v.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("endpoint replied {:?}", status.trim_end())))
+    }
+}
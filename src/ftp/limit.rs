@@ -0,0 +1,141 @@
+//! Session and per address connection limits and temporary bans after failed logins.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Longest ban, later bans of the same address do not grow beyond it
+const BAN_MAX: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Failures and bans of an address are forgotten this long after the last one
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Why a connection is refused
+#[derive(Debug)]
+pub enum Refused {
+    // remaining time of the ban
+    Banned(Duration),
+    TooManyConnections,
+    // all sessions in use
+    ServerFull,
+}
+
+#[derive(Default)]
+struct Address {
+    // open control connections
+    connections: usize,
+    // failed logins since the last ban, a successful login does not reset them
+    failures: u32,
+    // bans so far, each one twice as long as the one before
+    bans: u32,
+    last_failure: Option<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl Address {
+    fn banned(&self, now: Instant) -> Option<Duration> {
+        self.banned_until.filter(|v| *v > now).map(|v| v - now)
+    }
+
+    // nothing happened for a long time
+    fn quiet(&self, now: Instant) -> bool {
+        let last = self.banned_until.max(self.last_failure);
+        last.is_none_or(|v| now.saturating_duration_since(v) > FORGET_AFTER)
+    }
+}
+
+/// Connection and login failure accounting of all client addresses
+pub struct Limiter {
+    // session slots
+    sessions: Arc<Semaphore>,
+    // connections per address (0 = unlimited)
+    max_connections: usize,
+    // failed logins before a ban (0 = never)
+    ban_after: u32,
+    // duration of the first ban
+    ban_time: Duration,
+    addresses: Mutex<HashMap<IpAddr, Address>>,
+}
+
+impl Limiter {
+    /// `max_sessions` 0 is unlimited
+    pub fn new(max_sessions: usize, max_connections: usize, ban_after: u32, ban_time: Duration) -> Self {
+        let max_sessions = match max_sessions {
+            0 => Semaphore::MAX_PERMITS,
+            n => n
+        };
+        Limiter {
+            sessions: Arc::new(Semaphore::new(max_sessions)),
+            max_connections,
+            ban_after,
+            ban_time,
+            addresses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a new control connection, refused while the address is banned or has too many,
+    /// or when the server is full.
+    pub fn connect(self: &Arc<Self>, peer: SocketAddr) -> Result<Client, Refused> {
+        let now = Instant::now();
+        let mut addresses = self.addresses.lock().unwrap();
+        addresses.retain(|_, v| v.connections > 0 || !v.quiet(now));
+        let address = addresses.entry(peer.ip()).or_default();
+        if let Some(v) = address.banned(now) {
+            return Err(Refused::Banned(v));
+        }
+        if self.max_connections != 0 && address.connections >= self.max_connections {
+            return Err(Refused::TooManyConnections);
+        }
+        let permit = self.sessions.clone().try_acquire_owned().map_err(|_| Refused::ServerFull)?;
+        address.connections += 1;
+        Ok(Client { limiter: self.clone(), peer, _permit: permit })
+    }
+}
+
+/// A counted control connection, released on drop
+pub struct Client {
+    limiter: Arc<Limiter>,
+    pub peer: SocketAddr,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Client {
+    /// Remaining time of a ban of the address, it may come from another session.
+    pub fn banned(&self) -> Option<Duration> {
+        let addresses = self.limiter.addresses.lock().unwrap();
+        addresses.get(&self.peer.ip()).and_then(|v| v.banned(Instant::now()))
+    }
+
+    /// Count a failed login, returns the duration of the ban it caused.
+    pub fn login_failed(&self) -> Option<Duration> {
+        let limiter = &self.limiter;
+        let now = Instant::now();
+        let mut addresses = limiter.addresses.lock().unwrap();
+        let address = addresses.entry(self.peer.ip()).or_default();
+        if address.quiet(now) {
+            address.failures = 0;
+            address.bans = 0;
+        }
+        address.failures += 1;
+        address.last_failure = Some(now);
+        if limiter.ban_after == 0 || address.failures < limiter.ban_after {
+            return None;
+        }
+        // ban_time, 2 * ban_time, 4 * ban_time, ...
+        let ban = limiter.ban_time.saturating_mul(1 << address.bans.min(16)).min(BAN_MAX);
+        address.failures = 0;
+        address.bans += 1;
+        address.banned_until = Some(now + ban);
+        Some(ban)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(v) = self.limiter.addresses.lock().unwrap().get_mut(&self.peer.ip()) {
+            v.connections -= 1;
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Server replies (RFC959 4.2).
+
+/// A reply with its text, single or multi-line.
+///
+/// ```text
+/// 211-Features:
+///  EPSV
+///  SIZE
+/// 211 End
+/// ```
+#[derive(Debug, Clone)]
+pub struct Reply {
+    code: i32,
+    text: String,
+    // lines between the first and the last one
+    lines: Vec<String>,
+    end: String,
+}
+
+impl Reply {
+    /// Reply with the standard text of the code
+    pub fn new(code: i32) -> Self {
+        Reply::text(code, default_text(code))
+    }
+
+    pub fn text(code: i32, text: &str) -> Self {
+        Reply { code, text: one_line(text), lines: Vec::new(), end: String::from("End") }
+    }
+
+    /// Add a line to a multi-line reply. It is sent with a leading space,
+    /// so it can never be taken for the last line.
+    pub fn line(mut self, text: &str) -> Self {
+        self.lines.push(one_line(text));
+        self
+    }
+
+    /// Text of the last line of a multi-line reply
+    pub fn end(mut self, text: &str) -> Self {
+        self.end = one_line(text);
+        self
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.lines.is_empty() {
+            return format!("{} {}\r\n", self.code, self.text).into_bytes()
+        }
+        let mut message = format!("{}-{}\r\n", self.code, self.text);
+        for line in self.lines.iter() {
+            message.push_str(&format!(" {}\r\n", line));
+        }
+        message.push_str(&format!("{} {}\r\n", self.code, self.end));
+        message.into_bytes()
+    }
+}
+
+// A line break in a file name must not end the reply early.
+fn one_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+/// Standard text of a reply code, by its first digits for codes without one.
+fn default_text(code: i32) -> &'static str {
+    match code {
+        110 => "Restart marker reply.",
+        120 => "Service ready in a few minutes.",
+        125 => "Data connection already open; transfer starting.",
+        150 => "File status okay; about to open data connection.",
+        200 => "Command okay.",
+        202 => "Command not implemented, superfluous at this site.",
+        211 => "System status, or system help reply.",
+        212 => "Directory status.",
+        213 => "File status.",
+        214 => "Help message.",
+        220 => "Service ready for new user.",
+        221 => "Service closing control connection.",
+        225 => "Data connection open; no transfer in progress.",
+        226 => "Closing data connection.",
+        230 => "User logged in, proceed.",
+        234 => "Security data exchange complete.",
+        250 => "Requested file action okay, completed.",
+        331 => "User name okay, need password.",
+        332 => "Need account for login.",
+        350 => "Requested file action pending further information.",
+        421 => "Service not available, closing control connection.",
+        425 => "Can't open data connection.",
+        426 => "Connection closed; transfer aborted.",
+        431 => "Need some unavailable resource to process security.",
+        450 => "Requested file action not taken.",
+        451 => "Requested action aborted: local error in processing.",
+        452 => "Requested action not taken.",
+        500 => "Syntax error, command unrecognized.",
+        501 => "Syntax error in parameters or arguments.",
+        502 => "Command not implemented.",
+        503 => "Bad sequence of commands.",
+        504 => "Command not implemented for that parameter.",
+        521 => "Data connection cannot be opened with this PROT setting.",
+        522 => "Network protocol not supported.",
+        530 => "Not logged in.",
+        532 => "Need account for storing files.",
+        534 => "Request denied for policy reasons.",
+        536 => "Requested PROT level not supported by mechanism.",
+        550 => "Requested action not taken.",
+        551 => "Requested action aborted: page type unknown.",
+        552 => "Requested file action aborted.",
+        553 => "Requested action not taken.",
+        554 => "Requested action not taken: invalid REST parameter.",
+        _ => match code / 100 {
+            1 => "Positive preliminary reply.",
+            2 => "Positive completion reply.",
+            3 => "Positive intermediate reply.",
+            4 => "Transient negative completion reply.",
+            _ => "Permanent negative completion reply."
+        }
+    }
+}
@@ -0,0 +1,754 @@
+//! Storage backends of ftpd.
+//! Paths are the ones the client sees, absolute and normalized by the session ("/", "/a/b.txt").
+
+use std::collections::BTreeMap;
+use std::fs::{File, Metadata};
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Readable side of a file
+pub type ReadStream = Box<dyn AsyncRead + Send + Unpin>;
+/// Writable side of a file
+pub type WriteStream = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// A file or directory
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// last path component, "/" for the root
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// unix permission bits
+    pub mode: u32,
+    /// identifies the file across renames (RFC3659 7.5.2)
+    pub unique: Option<String>,
+}
+
+/// How STOR, APPE and REST + STOR open a file
+#[derive(Debug, Clone, Copy)]
+pub enum WriteMode {
+    Create,
+    Append,
+    // cut the file at the offset and write from there
+    Restart(u64),
+}
+
+/// Files of a session.
+/// Calls are synchronous: besides listings they are single short file system calls,
+/// file contents go through tokio. ftpd lists directories on the blocking pool.
+pub trait Storage: Send + Sync {
+    fn stat(&self, path: &str) -> io::Result<Entry>;
+    /// Entries of a directory, unsorted
+    fn list(&self, path: &str) -> io::Result<Vec<Entry>>;
+    /// Read a regular file from an offset, InvalidInput when it is beyond the end.
+    fn read(&self, path: &str, offset: u64) -> io::Result<ReadStream>;
+    /// Write a regular file, InvalidInput when a restart offset is beyond the end.
+    fn write(&self, path: &str, mode: WriteMode) -> io::Result<WriteStream>;
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    /// Delete a regular file
+    fn delete(&self, path: &str) -> io::Result<()>;
+    fn mkdir(&self, path: &str) -> io::Result<()>;
+    /// Remove an empty directory
+    fn rmdir(&self, path: &str) -> io::Result<()>;
+    fn set_modified(&self, path: &str, modified: SystemTime) -> io::Result<()>;
+    fn set_mode(&self, path: &str, mode: u32) -> io::Result<()>;
+}
+
+/// Where sessions keep their files
+pub enum Backend {
+    // directories on disk
+    Local,
+    // the ftp root and the tree shared by all sessions
+    Memory(PathBuf, MemoryStorage),
+}
+
+impl Backend {
+    /// Storage of a session, rooted at a home directory under the ftp root.
+    pub fn open(&self, home: &Path) -> io::Result<Arc<dyn Storage>> {
+        match self {
+            Backend::Local => {
+                std::fs::create_dir_all(home)?;
+                Ok(Arc::new(LocalStorage::new(home.to_path_buf())?))
+            },
+            Backend::Memory(root, memory) => {
+                let relative = home.strip_prefix(root).map_err(|_| outside_root())?;
+                let mut dir = String::new();
+                for component in relative.components() {
+                    match component {
+                        Component::Normal(v) => dir = format!("{}/{}", dir, v.to_string_lossy()),
+                        _ => return Err(outside_root())
+                    }
+                }
+                Ok(Arc::new(memory.home(&dir)?))
+            }
+        }
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "No such file or directory.")
+}
+
+fn not_a_file() -> io::Error {
+    io::Error::other("Not a regular file.")
+}
+
+fn not_a_dir() -> io::Error {
+    io::Error::other("Not a directory.")
+}
+
+fn outside_root() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Path leaves the ftp root.")
+}
+
+fn beyond_end() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "REST offset beyond end of file.")
+}
+
+// last component of a session path
+fn file_name(path: &str) -> String {
+    match path.rsplit('/').next() {
+        Some("") | None => String::from("/"),
+        Some(v) => v.to_string()
+    }
+}
+
+/// Files on disk below a root directory.
+/// A path can only name a file below the root, `..`, absolute components
+/// and symbolic links that lead out of the root are refused.
+pub struct LocalStorage {
+    root: PathBuf,
+    // root with all symbolic links resolved
+    real_root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> io::Result<Self> {
+        let real_root = root.canonicalize()?;
+        Ok(LocalStorage { root, real_root })
+    }
+
+    // local path of a session path
+    fn local_path(&self, path: &str) -> io::Result<PathBuf> {
+        let mut local = self.root.clone();
+        for name in path.split('/').filter(|v| !v.is_empty()) {
+            if name == "." || name == ".." || Path::new(name).components().count() != 1 {
+                return Err(outside_root())
+            }
+            local = local_name(&local, name);
+        }
+        self.confine(&local)?;
+        Ok(local)
+    }
+
+    // The deepest existing part of the path decides where it really leads,
+    // the rest is created below it.
+    fn confine(&self, local: &Path) -> io::Result<()> {
+        let mut existing = local;
+        loop {
+            match existing.canonicalize() {
+                Ok(real) if real.starts_with(&self.real_root) => return Ok(()),
+                Ok(_) => return Err(outside_root()),
+                // a dangling link would be followed by a create
+                Err(e) if e.kind() == io::ErrorKind::NotFound && existing.symlink_metadata().is_err() => {
+                    existing = existing.parent().ok_or_else(outside_root)?;
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(outside_root()),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn stat(&self, path: &str) -> io::Result<Entry> {
+        let local = self.local_path(path)?;
+        Ok(local_entry(file_name(path), &local.metadata()?))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for elm in std::fs::read_dir(self.local_path(path)?)? {
+            let elm = elm?;
+            // links out of the root are not shown
+            if self.confine(&elm.path()).is_err() {
+                continue;
+            }
+            // skip entries that vanish or cannot be read
+            if let Ok(meta) = elm.path().metadata() {
+                entries.push(local_entry(display_name(&elm.file_name()), &meta));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, path: &str, offset: u64) -> io::Result<ReadStream> {
+        let mut file = File::open(self.local_path(path)?)?;
+        let meta = file.metadata()?;
+        if !meta.is_file() {
+            return Err(not_a_file())
+        }
+        if offset > meta.len() {
+            return Err(beyond_end())
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(tokio::fs::File::from_std(file)))
+    }
+
+    fn write(&self, path: &str, mode: WriteMode) -> io::Result<WriteStream> {
+        let local = self.local_path(path)?;
+        let file = match mode {
+            WriteMode::Create => File::create(local)?,
+            WriteMode::Append => File::options().append(true).create(true).open(local)?,
+            WriteMode::Restart(offset) => {
+                let mut file = File::options().write(true).create(true).truncate(false).open(local)?;
+                if offset > file.metadata()?.len() {
+                    return Err(beyond_end())
+                }
+                file.set_len(offset)?;
+                file.seek(SeekFrom::Start(offset))?;
+                file
+            }
+        };
+        Ok(Box::new(tokio::fs::File::from_std(file)))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        std::fs::rename(self.local_path(from)?, self.local_path(to)?)
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        let local = self.local_path(path)?;
+        if !local.is_file() {
+            return Err(not_a_file())
+        }
+        std::fs::remove_file(local)
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        std::fs::create_dir(self.local_path(path)?)
+    }
+
+    fn rmdir(&self, path: &str) -> io::Result<()> {
+        std::fs::remove_dir(self.local_path(path)?)
+    }
+
+    fn set_modified(&self, path: &str, modified: SystemTime) -> io::Result<()> {
+        File::options().write(true).open(self.local_path(path)?)?.set_modified(modified)
+    }
+
+    fn set_mode(&self, path: &str, mode: u32) -> io::Result<()> {
+        set_local_mode(&self.local_path(path)?, mode)
+    }
+}
+
+fn local_entry(name: String, meta: &Metadata) -> Entry {
+    Entry {
+        name,
+        is_dir: meta.is_dir(),
+        size: meta.len(),
+        modified: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        mode: local_mode(meta),
+        unique: unique_id(meta),
+    }
+}
+
+/// Entry of a directory as the client names it.
+/// A name that is not UTF-8 on disk is listed as Latin-1 (see `display_name`) and found again here.
+#[cfg(unix)]
+fn local_name(dir: &Path, name: &str) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    let path = dir.join(name);
+    if path.symlink_metadata().is_ok() || !name.chars().all(|c| (c as u32) < 256) {
+        return path
+    }
+    let raw: Vec<u8> = name.chars().map(|c| c as u8).collect();
+    let latin1 = dir.join(std::ffi::OsStr::from_bytes(&raw));
+    if latin1.symlink_metadata().is_ok() { latin1 } else { path }
+}
+
+#[cfg(not(unix))]
+fn local_name(dir: &Path, name: &str) -> PathBuf {
+    dir.join(name)
+}
+
+/// File name for listings, never lossy on unix.
+#[cfg(unix)]
+fn display_name(name: &std::ffi::OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    super::command::decode_text(name.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn display_name(name: &std::ffi::OsStr) -> String {
+    name.to_string_lossy().into_owned()
+}
+
+#[cfg(unix)]
+fn local_mode(meta: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o7777
+}
+
+// Only the write bit is known here.
+#[cfg(not(unix))]
+fn local_mode(meta: &Metadata) -> u32 {
+    let mode = if meta.is_dir() { 0o555 } else { 0o444 };
+    if meta.permissions().readonly() { mode } else { mode | 0o222 }
+}
+
+#[cfg(unix)]
+fn set_local_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+// Only the write bit has a meaning here.
+#[cfg(not(unix))]
+fn set_local_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    std::fs::set_permissions(path, permissions)
+}
+
+// device and inode identify a file across renames
+#[cfg(unix)]
+fn unique_id(meta: &Metadata) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    Some(format!("{:x}U{:x}", meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn unique_id(_meta: &Metadata) -> Option<String> {
+    None
+}
+
+/// A file or directory in memory
+#[derive(Debug)]
+enum Node {
+    File { data: Vec<u8>, modified: SystemTime, mode: u32 },
+    Dir { modified: SystemTime, mode: u32 },
+}
+
+// nodes by absolute path, "" is the root
+type Tree = Arc<Mutex<BTreeMap<String, Node>>>;
+
+/// Files in memory, lost when the server stops.
+/// Sessions share the tree, each one sees it from its home directory.
+#[derive(Clone)]
+pub struct MemoryStorage {
+    // home of the session, "" for the root
+    root: String,
+    tree: Tree,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage::new()
+    }
+}
+
+impl MemoryStorage {
+    /// An empty tree
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(String::new(), Node::Dir { modified: SystemTime::now(), mode: 0o755 });
+        MemoryStorage { root: String::new(), tree: Arc::new(Mutex::new(nodes)) }
+    }
+
+    /// The same tree seen from a directory, e.g. "/alice". The directory is created if needed.
+    pub fn home(&self, dir: &str) -> io::Result<MemoryStorage> {
+        let mut nodes = self.tree.lock().unwrap();
+        let mut key = self.root.clone();
+        for name in dir.split('/').filter(|v| !v.is_empty()) {
+            key = format!("{}/{}", key, name);
+            match nodes.get(&key) {
+                Some(Node::Dir { .. }) => (),
+                Some(Node::File { .. }) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Home is a file.")),
+                None => { nodes.insert(key.clone(), Node::Dir { modified: SystemTime::now(), mode: 0o755 }); }
+            }
+        }
+        Ok(MemoryStorage { root: key, tree: self.tree.clone() })
+    }
+
+    // key of a session path
+    fn key(&self, path: &str) -> io::Result<String> {
+        let mut key = self.root.clone();
+        for name in path.split('/').filter(|v| !v.is_empty()) {
+            if name == "." || name == ".." {
+                return Err(outside_root())
+            }
+            key = format!("{}/{}", key, name);
+        }
+        Ok(key)
+    }
+}
+
+// key of the directory holding a key
+fn parent_key(key: &str) -> &str {
+    key.rsplit_once('/').map(|v| v.0).unwrap_or("")
+}
+
+// A new node needs an existing parent directory.
+fn check_parent(nodes: &BTreeMap<String, Node>, key: &str) -> io::Result<()> {
+    match nodes.get(parent_key(key)) {
+        Some(Node::Dir { .. }) => Ok(()),
+        Some(Node::File { .. }) => Err(not_a_dir()),
+        None => Err(not_found())
+    }
+}
+
+fn memory_entry(name: String, node: &Node) -> Entry {
+    let (is_dir, size, modified, mode) = match node {
+        Node::File { data, modified, mode } => (false, data.len() as u64, *modified, *mode),
+        Node::Dir { modified, mode } => (true, 0, *modified, *mode)
+    };
+    Entry { name, is_dir, size, modified, mode, unique: None }
+}
+
+impl Storage for MemoryStorage {
+    fn stat(&self, path: &str) -> io::Result<Entry> {
+        let key = self.key(path)?;
+        let nodes = self.tree.lock().unwrap();
+        let node = nodes.get(&key).ok_or_else(not_found)?;
+        Ok(memory_entry(file_name(path), node))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let key = self.key(path)?;
+        let nodes = self.tree.lock().unwrap();
+        match nodes.get(&key) {
+            Some(Node::Dir { .. }) => (),
+            Some(Node::File { .. }) => return Err(not_a_dir()),
+            None => return Err(not_found())
+        }
+        let prefix = format!("{}/", key);
+        let entries = nodes.range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter(|(k, _)| !k[prefix.len()..].contains('/'))
+            .map(|(k, v)| memory_entry(k[prefix.len()..].to_string(), v))
+            .collect();
+        Ok(entries)
+    }
+
+    fn read(&self, path: &str, offset: u64) -> io::Result<ReadStream> {
+        let key = self.key(path)?;
+        let nodes = self.tree.lock().unwrap();
+        let data = match nodes.get(&key) {
+            Some(Node::File { data, .. }) => data,
+            Some(Node::Dir { .. }) => return Err(not_a_file()),
+            None => return Err(not_found())
+        };
+        if offset > data.len() as u64 {
+            return Err(beyond_end())
+        }
+        // a snapshot, later writes do not change a running download
+        Ok(Box::new(io::Cursor::new(data[offset as usize..].to_vec())))
+    }
+
+    fn write(&self, path: &str, mode: WriteMode) -> io::Result<WriteStream> {
+        let key = self.key(path)?;
+        let mut nodes = self.tree.lock().unwrap();
+        check_parent(&nodes, &key)?;
+        let position = match (nodes.get_mut(&key), mode) {
+            (Some(Node::Dir { .. }), _) => return Err(not_a_file()),
+            (Some(Node::File { data, .. }), WriteMode::Create) => { data.clear(); 0 },
+            (Some(Node::File { data, .. }), WriteMode::Append) => data.len(),
+            (Some(Node::File { data, .. }), WriteMode::Restart(offset)) => {
+                if offset > data.len() as u64 {
+                    return Err(beyond_end())
+                }
+                data.truncate(offset as usize);
+                data.len()
+            },
+            (None, WriteMode::Restart(offset)) if offset > 0 => return Err(beyond_end()),
+            (None, _) => {
+                nodes.insert(key.clone(), Node::File { data: Vec::new(), modified: SystemTime::now(), mode: 0o644 });
+                0
+            }
+        };
+        Ok(Box::new(MemoryWriter { tree: self.tree.clone(), key, position }))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let from = self.key(from)?;
+        let to = self.key(to)?;
+        let mut nodes = self.tree.lock().unwrap();
+        if !nodes.contains_key(&from) {
+            return Err(not_found())
+        }
+        if nodes.contains_key(&to) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Target exists."))
+        }
+        // a directory cannot move into itself
+        if from.is_empty() || to.starts_with(&format!("{}/", from)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid rename."))
+        }
+        check_parent(&nodes, &to)?;
+        // the node and, for a directory, everything below it
+        let prefix = format!("{}/", from);
+        let keys: Vec<String> = nodes.keys()
+            .filter(|k| **k == from || k.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for key in keys {
+            let node = nodes.remove(&key).unwrap();
+            nodes.insert(format!("{}{}", to, &key[from.len()..]), node);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> io::Result<()> {
+        let key = self.key(path)?;
+        let mut nodes = self.tree.lock().unwrap();
+        match nodes.get(&key) {
+            Some(Node::File { .. }) => { nodes.remove(&key); Ok(()) },
+            Some(Node::Dir { .. }) => Err(not_a_file()),
+            None => Err(not_found())
+        }
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        let key = self.key(path)?;
+        let mut nodes = self.tree.lock().unwrap();
+        if nodes.contains_key(&key) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "File exists."))
+        }
+        check_parent(&nodes, &key)?;
+        nodes.insert(key, Node::Dir { modified: SystemTime::now(), mode: 0o755 });
+        Ok(())
+    }
+
+    fn rmdir(&self, path: &str) -> io::Result<()> {
+        let key = self.key(path)?;
+        let mut nodes = self.tree.lock().unwrap();
+        match nodes.get(&key) {
+            Some(Node::Dir { .. }) if key != self.root => (),
+            Some(_) => return Err(not_a_dir()),
+            None => return Err(not_found())
+        }
+        let prefix = format!("{}/", key);
+        if nodes.range(prefix.clone()..).next().is_some_and(|(k, _)| k.starts_with(&prefix)) {
+            return Err(io::Error::other("Directory not empty."))
+        }
+        nodes.remove(&key);
+        Ok(())
+    }
+
+    fn set_modified(&self, path: &str, time: SystemTime) -> io::Result<()> {
+        let key = self.key(path)?;
+        let mut nodes = self.tree.lock().unwrap();
+        match nodes.get_mut(&key) {
+            Some(Node::File { modified, .. }) | Some(Node::Dir { modified, .. }) => { *modified = time; Ok(()) },
+            None => Err(not_found())
+        }
+    }
+
+    fn set_mode(&self, path: &str, value: u32) -> io::Result<()> {
+        let key = self.key(path)?;
+        let mut nodes = self.tree.lock().unwrap();
+        match nodes.get_mut(&key) {
+            Some(Node::File { mode, .. }) | Some(Node::Dir { mode, .. }) => { *mode = value; Ok(()) },
+            None => Err(not_found())
+        }
+    }
+}
+
+/// Upload into a memory file. Data is stored as it arrives, like a file on disk.
+struct MemoryWriter {
+    tree: Tree,
+    key: String,
+    position: usize,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let writer = self.get_mut();
+        let mut nodes = writer.tree.lock().unwrap();
+        // the file may be deleted during the upload
+        let (data, modified) = match nodes.get_mut(&writer.key) {
+            Some(Node::File { data, modified, .. }) => (data, modified),
+            _ => return Poll::Ready(Err(not_found()))
+        };
+        let end = writer.position + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[writer.position..end].copy_from_slice(buf);
+        *modified = SystemTime::now();
+        writer.position = end;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn put(storage: &dyn Storage, path: &str, mode: WriteMode, data: &[u8]) -> io::Result<()> {
+        let mut file = storage.write(path, mode)?;
+        file.write_all(data).await?;
+        file.shutdown().await
+    }
+
+    async fn get(storage: &dyn Storage, path: &str, offset: u64) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        storage.read(path, offset)?.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    #[tokio::test]
+    async fn memory_write_modes() {
+        let storage = MemoryStorage::new();
+        put(&storage, "/a.txt", WriteMode::Create, b"hello").await.unwrap();
+        put(&storage, "/a.txt", WriteMode::Append, b" world").await.unwrap();
+        assert_eq!(get(&storage, "/a.txt", 0).await.unwrap(), b"hello world");
+        assert_eq!(get(&storage, "/a.txt", 6).await.unwrap(), b"world");
+
+        // restart cuts the file at the offset
+        put(&storage, "/a.txt", WriteMode::Restart(5), b"!").await.unwrap();
+        assert_eq!(get(&storage, "/a.txt", 0).await.unwrap(), b"hello!");
+
+        put(&storage, "/a.txt", WriteMode::Create, b"new").await.unwrap();
+        assert_eq!(get(&storage, "/a.txt", 0).await.unwrap(), b"new");
+        assert_eq!(storage.stat("/a.txt").unwrap().size, 3);
+
+        // appending creates a missing file
+        put(&storage, "/b.txt", WriteMode::Append, b"b").await.unwrap();
+        assert_eq!(get(&storage, "/b.txt", 0).await.unwrap(), b"b");
+    }
+
+    #[tokio::test]
+    async fn memory_restart_beyond_end() {
+        let storage = MemoryStorage::new();
+        put(&storage, "/a.txt", WriteMode::Create, b"abc").await.unwrap();
+        let kind = |r: io::Result<WriteStream>| r.err().map(|e| e.kind());
+        assert_eq!(kind(storage.write("/a.txt", WriteMode::Restart(4))), Some(io::ErrorKind::InvalidInput));
+        assert_eq!(kind(storage.write("/new.txt", WriteMode::Restart(1))), Some(io::ErrorKind::InvalidInput));
+        assert_eq!(storage.read("/a.txt", 4).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        // the end itself is fine
+        assert_eq!(get(&storage, "/a.txt", 3).await.unwrap(), b"");
+        put(&storage, "/a.txt", WriteMode::Restart(3), b"d").await.unwrap();
+        assert_eq!(get(&storage, "/a.txt", 0).await.unwrap(), b"abcd");
+        // the refused restarts left everything as it was
+        assert!(storage.stat("/new.txt").is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_rename_subtree() {
+        let storage = MemoryStorage::new();
+        storage.mkdir("/a").unwrap();
+        storage.mkdir("/a/b").unwrap();
+        put(&storage, "/a/b/f.txt", WriteMode::Create, b"f").await.unwrap();
+        put(&storage, "/ab.txt", WriteMode::Create, b"ab").await.unwrap();
+
+        storage.rename("/a", "/c").unwrap();
+        assert!(storage.stat("/a").is_err());
+        assert!(storage.stat("/c/b").unwrap().is_dir);
+        assert_eq!(get(&storage, "/c/b/f.txt", 0).await.unwrap(), b"f");
+        // a sibling sharing the name prefix stays
+        assert_eq!(get(&storage, "/ab.txt", 0).await.unwrap(), b"ab");
+
+        assert_eq!(storage.rename("/c", "/c/b/d").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(storage.rename("/c", "/ab.txt").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(storage.rename("/missing", "/x").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(storage.rename("/ab.txt", "/none/ab.txt").is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_rmdir() {
+        let storage = MemoryStorage::new();
+        storage.mkdir("/d").unwrap();
+        put(&storage, "/d/f.txt", WriteMode::Create, b"f").await.unwrap();
+        assert!(storage.rmdir("/d").is_err());
+        assert!(storage.rmdir("/d/f.txt").is_err());
+        storage.delete("/d/f.txt").unwrap();
+        storage.rmdir("/d").unwrap();
+        assert!(storage.stat("/d").is_err());
+        assert!(storage.rmdir("/").is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_home() {
+        let tree = MemoryStorage::new();
+        let alice = tree.home("/alice").unwrap();
+        put(&alice, "/a.txt", WriteMode::Create, b"a").await.unwrap();
+        assert_eq!(get(&tree, "/alice/a.txt", 0).await.unwrap(), b"a");
+        assert_eq!(alice.stat("/..").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(alice.rmdir("/").is_err());
+    }
+
+    // a fresh directory below the system's temporary directory
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ntk-rfc-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn local_confine_dot_dot() {
+        let dir = temp_dir("dotdot");
+        std::fs::create_dir(dir.join("home")).unwrap();
+        std::fs::write(dir.join("secret.txt"), b"secret").unwrap();
+        let storage = LocalStorage::new(dir.join("home")).unwrap();
+
+        for path in ["/../secret.txt", "/..", "/a/../../secret.txt", "/./x"] {
+            assert_eq!(storage.stat(path).unwrap_err().kind(), io::ErrorKind::PermissionDenied, "{}", path);
+        }
+        assert_eq!(storage.write("/../new.txt", WriteMode::Create).err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+        assert!(!dir.join("new.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn local_confine_symlinks() {
+        use std::os::unix::fs::symlink;
+        let dir = temp_dir("symlink");
+        let home = dir.join("home");
+        std::fs::create_dir_all(home.join("sub")).unwrap();
+        std::fs::create_dir(dir.join("outside")).unwrap();
+        std::fs::write(dir.join("outside/secret.txt"), b"secret").unwrap();
+        symlink(dir.join("outside"), home.join("out")).unwrap();
+        symlink(dir.join("outside/secret.txt"), home.join("secret.txt")).unwrap();
+        symlink(dir.join("missing"), home.join("dangling")).unwrap();
+        // a link that stays inside is fine
+        symlink(home.join("sub"), home.join("inside")).unwrap();
+        let storage = LocalStorage::new(home.clone()).unwrap();
+
+        let denied = |r: io::Result<Entry>| r.err().map(|e| e.kind()) == Some(io::ErrorKind::PermissionDenied);
+        assert!(denied(storage.stat("/out")));
+        assert!(denied(storage.stat("/out/secret.txt")));
+        assert!(denied(storage.stat("/secret.txt")));
+        assert!(storage.write("/out/new.txt", WriteMode::Create).is_err());
+        assert!(storage.write("/dangling", WriteMode::Create).is_err());
+        assert!(storage.mkdir("/out/dir").is_err());
+        assert!(!dir.join("outside/new.txt").exists());
+        assert!(!dir.join("missing").exists());
+
+        assert!(storage.stat("/inside").unwrap().is_dir);
+        storage.write("/inside/a.txt", WriteMode::Create).unwrap();
+        assert!(home.join("sub/a.txt").exists());
+        // listings leave out links that lead outside
+        let mut names: Vec<String> = storage.list("/").unwrap().into_iter().map(|v| v.name).collect();
+        names.sort();
+        assert_eq!(names, ["inside", "sub"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
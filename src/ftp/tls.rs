@@ -0,0 +1,103 @@
+//! Explicit FTPS (RFC4217): AUTH TLS on the control connection, PROT P on data connections.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{self, ServerConfig};
+use tokio_rustls::rustls::server::ServerSessionMemoryCache;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Sessions remembered per control connection, its own and a few of reconnecting data connections
+const SESSION_CACHE: usize = 32;
+
+/// Build the TLS server side from a PEM certificate chain and private key.
+/// Every control connection works with a copy of it, see `session_acceptor`.
+pub fn load_acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No private key found."))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|v| v.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    // TLS 1.2 clients resume with tickets, TLS 1.3 clients with the session cache
+    config.ticketer = rustls::crypto::ring::Ticketer::new()
+        .map_err(io::Error::other)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The acceptor of one control connection and its data connections. It has a session cache
+/// and ticket keys of its own, so data connections can only resume this control session.
+pub fn session_acceptor(shared: &TlsAcceptor) -> io::Result<TlsAcceptor> {
+    let mut config = ServerConfig::clone(shared.config());
+    config.session_storage = ServerSessionMemoryCache::new(SESSION_CACHE);
+    config.ticketer = rustls::crypto::ring::Ticketer::new()
+        .map_err(io::Error::other)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// A control or data connection, before or after the TLS handshake
+pub enum FtpStream<S = TcpStream> {
+    Plain(S),
+    Tls(Box<TlsStream<S>>),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> FtpStream<S> {
+    /// Run the server side of the TLS handshake.
+    pub async fn start_tls(self, acceptor: &TlsAcceptor) -> io::Result<FtpStream<S>> {
+        match self {
+            FtpStream::Plain(v) => Ok(FtpStream::Tls(Box::new(acceptor.accept(v).await?))),
+            FtpStream::Tls(_) => Err(io::Error::other("TLS is already active."))
+        }
+    }
+
+    /// The TLS handshake resumed an earlier session of the acceptor,
+    /// with `session_acceptor` the one of the control connection.
+    pub fn is_resumed(&self) -> bool {
+        match self {
+            FtpStream::Plain(_) => false,
+            FtpStream::Tls(v) => v.get_ref().1.handshake_kind() == Some(rustls::HandshakeKind::Resumed)
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for FtpStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            FtpStream::Plain(v) => Pin::new(v).poll_read(cx, buf),
+            FtpStream::Tls(v) => Pin::new(v).poll_read(cx, buf)
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for FtpStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            FtpStream::Plain(v) => Pin::new(v).poll_write(cx, buf),
+            FtpStream::Tls(v) => Pin::new(v).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            FtpStream::Plain(v) => Pin::new(v).poll_flush(cx),
+            FtpStream::Tls(v) => Pin::new(v).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            FtpStream::Plain(v) => Pin::new(v).poll_shutdown(cx),
+            FtpStream::Tls(v) => Pin::new(v).poll_shutdown(cx)
+        }
+    }
+}
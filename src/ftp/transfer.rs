@@ -0,0 +1,615 @@
+//! Data connection encoding: RFC959 3.1 data representation and structure, 3.4 transmission modes.
+
+const CR: u8 = 13;
+const LF: u8 = 10;
+
+// Block and escape descriptor codes (RFC959 3.4.2)
+const DESC_EOR: u8 = 128;
+const DESC_EOF: u8 = 64;
+const DESC_MARKER: u8 = 16;
+
+// Stream mode control codes for record structure (RFC959 3.4.1)
+const STREAM_ESCAPE: u8 = 0xFF;
+const STREAM_EOR: u8 = 1;
+const STREAM_EOF: u8 = 2;
+
+/// Largest block in block mode
+const MAX_BLOCK: usize = 65535;
+/// Restart marker interval on the sending side, in bytes of the local file
+const MARKER_INTERVAL: u64 = 1024 * 1024;
+
+/// MODE
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Stream,
+    Block,
+    Compressed,
+}
+
+/// STRU
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Structure {
+    File,
+    // one record per local line
+    Record,
+}
+
+/// Turns local file data into what goes over the data connection.
+pub struct Encoder {
+    mode: Mode,
+    stru: Structure,
+    ascii: bool,
+    // last byte of the previous chunk, for CRLF conversion
+    last: u8,
+    // record structure: CR at the end of the previous chunk, it may start a line ending
+    held_cr: bool,
+    // local file offset, used as the restart marker
+    offset: u64,
+    marker_offset: u64,
+}
+
+impl Encoder {
+    pub fn new(mode: Mode, stru: Structure, ascii: bool, offset: u64) -> Self {
+        Encoder { mode, stru, ascii, last: 0, held_cr: false, offset, marker_offset: offset }
+    }
+
+    /// Encode a chunk of local file data.
+    pub fn encode(&mut self, buf: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(buf.len() + 16);
+        match self.stru {
+            Structure::File => {
+                if self.ascii {
+                    let data = to_crlf(buf, &mut self.last);
+                    self.put_data(&data, &mut out);
+                } else {
+                    self.put_data(buf, &mut out);
+                }
+            },
+            Structure::Record => {
+                let mut data = Vec::with_capacity(buf.len() + 1);
+                if std::mem::take(&mut self.held_cr) {
+                    data.push(CR);
+                }
+                data.extend(buf);
+                // every line is a record, the line ending itself is not sent
+                let mut start = 0;
+                for (i, &b) in data.iter().enumerate() {
+                    if b == LF {
+                        let record = &data[start..i];
+                        let record = record.strip_suffix(&[CR]).unwrap_or(record);
+                        self.put_data(record, &mut out);
+                        self.put_descriptor(DESC_EOR, &mut out);
+                        start = i + 1;
+                    }
+                }
+                let mut rest = &data[start..];
+                if let Some(v) = rest.strip_suffix(&[CR]) {
+                    rest = v;
+                    self.held_cr = true;
+                }
+                self.put_data(rest, &mut out);
+            }
+        }
+
+        self.offset += buf.len() as u64;
+        if self.mode != Mode::Stream && self.offset - self.marker_offset >= MARKER_INTERVAL {
+            self.marker_offset = self.offset;
+            self.put_marker(&mut out);
+        }
+        out
+    }
+
+    /// End of file.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        // a CR at the very end is data
+        if std::mem::take(&mut self.held_cr) {
+            self.put_data(&[CR], &mut out);
+        }
+        // a stream of file structure ends by closing the connection
+        if self.mode != Mode::Stream || self.stru == Structure::Record {
+            self.put_descriptor(DESC_EOF, &mut out);
+        }
+        out
+    }
+
+    fn put_data(&self, data: &[u8], out: &mut Vec<u8>) {
+        match self.mode {
+            Mode::Stream => {
+                if self.stru == Structure::Record {
+                    for &b in data {
+                        out.push(b);
+                        if b == STREAM_ESCAPE {
+                            out.push(STREAM_ESCAPE);
+                        }
+                    }
+                } else {
+                    out.extend(data);
+                }
+            },
+            Mode::Block => {
+                for chunk in data.chunks(MAX_BLOCK) {
+                    put_block(0, chunk, out);
+                }
+            },
+            Mode::Compressed => {
+                let filler = if self.ascii { b' ' } else { 0 };
+                compress(data, filler, out);
+            }
+        }
+    }
+
+    fn put_descriptor(&self, desc: u8, out: &mut Vec<u8>) {
+        match self.mode {
+            Mode::Stream => {
+                let code = if desc == DESC_EOR { STREAM_EOR } else { STREAM_EOF };
+                out.extend([STREAM_ESCAPE, code]);
+            },
+            Mode::Block => put_block(desc, &[], out),
+            Mode::Compressed => out.extend([0, desc])
+        }
+    }
+
+    // The marker is the local file offset, so REST accepts it as is.
+    fn put_marker(&self, out: &mut Vec<u8>) {
+        let marker = self.offset.to_string();
+        match self.mode {
+            Mode::Stream => (),
+            Mode::Block => put_block(DESC_MARKER, marker.as_bytes(), out),
+            Mode::Compressed => {
+                out.extend([0, DESC_MARKER, marker.len() as u8]);
+                out.extend(marker.as_bytes());
+            }
+        }
+    }
+}
+
+fn put_block(desc: u8, data: &[u8], out: &mut Vec<u8>) {
+    out.push(desc);
+    out.extend((data.len() as u16).to_be_bytes());
+    out.extend(data);
+}
+
+// Compressed mode: 0nnnnnnn regular data, 10nnnnnn replicated byte, 11nnnnnn filler
+fn compress(data: &[u8], filler: u8, out: &mut Vec<u8>) {
+    let mut literal: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        let run = data[i..].iter().take(63).take_while(|&&v| v == b).count();
+        if run >= 3 || (b == filler && run >= 2) {
+            flush_literal(&mut literal, out);
+            if b == filler {
+                out.push(0xC0 | run as u8);
+            } else {
+                out.extend([0x80 | run as u8, b]);
+            }
+            i += run;
+        } else {
+            literal.push(b);
+            if literal.len() == 127 {
+                flush_literal(&mut literal, out);
+            }
+            i += 1;
+        }
+    }
+    flush_literal(&mut literal, out);
+}
+
+fn flush_literal(literal: &mut Vec<u8>, out: &mut Vec<u8>) {
+    if !literal.is_empty() {
+        out.push(literal.len() as u8);
+        out.append(literal);
+    }
+}
+
+/// Turns what arrives over the data connection into local file data.
+pub struct Decoder {
+    mode: Mode,
+    stru: Structure,
+    ascii: bool,
+    // CR at the end of the previous chunk
+    pending_cr: bool,
+    // EOF descriptor received
+    eof: bool,
+    // stream mode: 0xFF received, compressed mode: 0x00 received
+    escape: bool,
+    // block mode: header being read
+    header: Vec<u8>,
+    in_block: bool,
+    desc: u8,
+    // bytes left in the current block or regular data segment
+    remaining: usize,
+    // compressed mode: count of the byte to replicate
+    repeat: usize,
+    // restart marker being collected
+    marker: Option<Vec<u8>>,
+}
+
+impl Decoder {
+    pub fn new(mode: Mode, stru: Structure, ascii: bool) -> Self {
+        Decoder {
+            mode, stru, ascii,
+            pending_cr: false,
+            eof: false,
+            escape: false,
+            header: Vec::new(),
+            in_block: false,
+            desc: 0,
+            remaining: 0,
+            repeat: 0,
+            marker: None,
+        }
+    }
+
+    /// Decode a chunk. Local data is appended to `out`.
+    /// Restart markers are returned with the length of `out` at the point they arrived.
+    pub fn decode(&mut self, buf: &[u8], out: &mut Vec<u8>) -> Vec<(String, usize)> {
+        let mut markers = Vec::new();
+        for &b in buf {
+            if self.eof {
+                break;
+            }
+            match self.mode {
+                Mode::Stream => self.decode_stream(b, out),
+                Mode::Block => self.decode_block(b, out, &mut markers),
+                Mode::Compressed => self.decode_compressed(b, out, &mut markers)
+            }
+        }
+        markers
+    }
+
+    /// The connection was closed. Returns false if the sender did not finish the file.
+    pub fn finish(&mut self, out: &mut Vec<u8>) -> bool {
+        if self.pending_cr {
+            self.pending_cr = false;
+            out.push(CR);
+        }
+        match (self.mode, self.stru) {
+            (Mode::Stream, Structure::File) => true,
+            (Mode::Stream, Structure::Record) => self.eof || !self.escape,
+            _ => self.eof
+        }
+    }
+
+    fn decode_stream(&mut self, b: u8, out: &mut Vec<u8>) {
+        if self.stru == Structure::File {
+            self.emit(b, out);
+            return;
+        }
+        if !self.escape {
+            if b == STREAM_ESCAPE {
+                self.escape = true;
+            } else {
+                self.emit(b, out);
+            }
+            return;
+        }
+        self.escape = false;
+        match b {
+            STREAM_ESCAPE => self.emit(b, out),
+            _ => {
+                if b & STREAM_EOR != 0 {
+                    self.end_record(out);
+                }
+                if b & STREAM_EOF != 0 {
+                    self.eof = true;
+                }
+            }
+        }
+    }
+
+    fn decode_block(&mut self, b: u8, out: &mut Vec<u8>, markers: &mut Vec<(String, usize)>) {
+        if !self.in_block {
+            self.header.push(b);
+            if self.header.len() == 3 {
+                self.desc = self.header[0];
+                self.remaining = u16::from_be_bytes([self.header[1], self.header[2]]) as usize;
+                self.header.clear();
+                self.in_block = true;
+                if self.desc & DESC_MARKER != 0 {
+                    self.marker = Some(Vec::new());
+                }
+                if self.remaining == 0 {
+                    self.end_block(out, markers);
+                }
+            }
+            return;
+        }
+        match self.marker.as_mut() {
+            Some(v) => v.push(b),
+            None => self.emit(b, out)
+        }
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.end_block(out, markers);
+        }
+    }
+
+    fn end_block(&mut self, out: &mut Vec<u8>, markers: &mut Vec<(String, usize)>) {
+        self.in_block = false;
+        if let Some(v) = self.marker.take() {
+            markers.push((String::from_utf8_lossy(&v).into_owned(), out.len()));
+        }
+        if self.desc & DESC_EOR != 0 {
+            self.end_record(out);
+        }
+        if self.desc & DESC_EOF != 0 {
+            self.eof = true;
+        }
+    }
+
+    fn decode_compressed(&mut self, b: u8, out: &mut Vec<u8>, markers: &mut Vec<(String, usize)>) {
+        if self.repeat > 0 {
+            for _ in 0..self.repeat {
+                self.emit(b, out);
+            }
+            self.repeat = 0;
+            return;
+        }
+        if self.escape {
+            self.escape = false;
+            if b & DESC_EOR != 0 {
+                self.end_record(out);
+            }
+            if b & DESC_EOF != 0 {
+                self.eof = true;
+            }
+            // the next regular data segment carries the marker
+            if b & DESC_MARKER != 0 {
+                self.marker = Some(Vec::new());
+            }
+            return;
+        }
+        if self.remaining > 0 {
+            match self.marker.as_mut() {
+                Some(v) => v.push(b),
+                None => self.emit(b, out)
+            }
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                if let Some(v) = self.marker.take() {
+                    markers.push((String::from_utf8_lossy(&v).into_owned(), out.len()));
+                }
+            }
+            return;
+        }
+        match b {
+            0 => self.escape = true,
+            0x01..=0x7F => self.remaining = b as usize,
+            0x80..=0xBF => self.repeat = (b & 0x3F) as usize,
+            _ => {
+                let filler = if self.ascii { b' ' } else { 0 };
+                for _ in 0..(b & 0x3F) {
+                    self.emit(filler, out);
+                }
+            }
+        }
+    }
+
+    fn emit(&mut self, b: u8, out: &mut Vec<u8>) {
+        if self.ascii && self.stru == Structure::File && !cfg!(windows) {
+            from_crlf(&[b], &mut self.pending_cr, out);
+        } else {
+            out.push(b);
+        }
+    }
+
+    // records become local lines
+    fn end_record(&mut self, out: &mut Vec<u8>) {
+        if self.stru == Structure::Record {
+            if cfg!(windows) {
+                out.push(CR);
+            }
+            out.push(LF);
+        }
+    }
+}
+
+// LF -> CRLF, keeping existing CRLF pairs
+pub fn to_crlf(buf: &[u8], last: &mut u8) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len() + buf.len() / 16);
+    for &b in buf {
+        if b == LF && *last != CR {
+            out.push(CR);
+        }
+        out.push(b);
+        *last = b;
+    }
+    out
+}
+
+// CRLF -> LF, a lone CR is kept
+pub fn from_crlf(buf: &[u8], pending_cr: &mut bool, out: &mut Vec<u8>) {
+    for &b in buf {
+        if *pending_cr {
+            *pending_cr = false;
+            if b != LF {
+                out.push(CR);
+            }
+        }
+        if b == CR {
+            *pending_cr = true;
+            continue;
+        }
+        out.push(b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // local line ending of records
+    const EOL: &[u8] = if cfg!(windows) { b"\r\n" } else { b"\n" };
+
+    fn encode(encoder: &mut Encoder, data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for v in data.chunks(chunk) {
+            out.extend(encoder.encode(v));
+        }
+        out.extend(encoder.finish());
+        out
+    }
+
+    // (local data, restart markers, complete)
+    fn decode(decoder: &mut Decoder, wire: &[u8], chunk: usize) -> (Vec<u8>, Vec<(String, usize)>, bool) {
+        let mut out = Vec::new();
+        let mut markers = Vec::new();
+        for v in wire.chunks(chunk) {
+            markers.extend(decoder.decode(v, &mut out));
+        }
+        let complete = decoder.finish(&mut out);
+        (out, markers, complete)
+    }
+
+    // not compressible, no runs
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let data = pattern(200_000);
+        let wire = encode(&mut Encoder::new(Mode::Block, Structure::File, false, 0), &data, 7000);
+        // blocks of at most 65535 bytes and the EOF block
+        assert_eq!(&wire[wire.len() - 3..], [DESC_EOF, 0, 0]);
+        for chunk in [1, 2, 4096, wire.len()] {
+            let (out, markers, complete) = decode(&mut Decoder::new(Mode::Block, Structure::File, false), &wire, chunk);
+            assert_eq!(out, data);
+            assert!(markers.is_empty());
+            assert!(complete);
+        }
+        // closed before the EOF block
+        let (_, _, complete) = decode(&mut Decoder::new(Mode::Block, Structure::File, false), &wire[..wire.len() - 3], 4096);
+        assert!(!complete);
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let mut data = pattern(300);
+        data.extend([0; 200]);
+        data.extend([b'x'; 70]);
+        data.extend(b"ab  cc ddd\n");
+        data.extend(pattern(1000));
+        for ascii in [false, true] {
+            let wire = encode(&mut Encoder::new(Mode::Compressed, Structure::File, ascii, 0), &data, 333);
+            assert!(wire.len() < data.len());
+            for chunk in [1, 5, wire.len()] {
+                let (out, _, complete) = decode(&mut Decoder::new(Mode::Compressed, Structure::File, ascii), &wire, chunk);
+                assert_eq!(out, data);
+                assert!(complete);
+            }
+        }
+    }
+
+    #[test]
+    fn compress_runs() {
+        let mut out = Vec::new();
+        compress(b"abcdddd\0\0e", 0, &mut out);
+        assert_eq!(out, [3, b'a', b'b', b'c', 0x84, b'd', 0xC2, 1, b'e']);
+        // runs are at most 63 bytes, literals at most 127
+        out.clear();
+        compress(&[b'z'; 64], b' ', &mut out);
+        assert_eq!(out, [0xBF, b'z', 1, b'z']);
+        out.clear();
+        compress(&pattern(130), 0, &mut out);
+        assert_eq!((out[0], out[128]), (127, 3));
+    }
+
+    #[test]
+    fn restart_markers() {
+        let data = pattern(3 * MARKER_INTERVAL as usize + 100);
+        for mode in [Mode::Block, Mode::Compressed] {
+            // the upload was restarted at 100, markers are offsets in the local file
+            let wire = encode(&mut Encoder::new(mode, Structure::File, false, 100), &data, 64 * 1024);
+            let (out, markers, complete) = decode(&mut Decoder::new(mode, Structure::File, false), &wire, 10_000);
+            assert_eq!(out, data);
+            assert!(complete);
+            let interval = MARKER_INTERVAL as usize;
+            let expected: Vec<(String, usize)> = (1..=3).map(|n| ((100 + n * interval).to_string(), n * interval)).collect();
+            assert_eq!(markers, expected);
+        }
+        // stream mode has no markers
+        let wire = encode(&mut Encoder::new(Mode::Stream, Structure::File, false, 0), &data, 64 * 1024);
+        assert_eq!(wire, data);
+    }
+
+    #[test]
+    fn record_descriptors() {
+        let data = b"ab\r\ncd\nef";
+        let mut encoder = Encoder::new(Mode::Block, Structure::Record, true, 0);
+        let wire = encode(&mut encoder, data, data.len());
+        assert_eq!(wire, [0, 0, 2, b'a', b'b', DESC_EOR, 0, 0, 0, 0, 2, b'c', b'd', DESC_EOR, 0, 0,
+            0, 0, 2, b'e', b'f', DESC_EOF, 0, 0]);
+
+        let mut encoder = Encoder::new(Mode::Compressed, Structure::Record, true, 0);
+        let wire = encode(&mut encoder, data, data.len());
+        assert_eq!(wire, [2, b'a', b'b', 0, DESC_EOR, 2, b'c', b'd', 0, DESC_EOR, 2, b'e', b'f', 0, DESC_EOF]);
+
+        for mode in [Mode::Block, Mode::Compressed] {
+            let wire = encode(&mut Encoder::new(mode, Structure::Record, true, 0), data, 3);
+            let (out, _, complete) = decode(&mut Decoder::new(mode, Structure::Record, true), &wire, 1);
+            assert_eq!(out, [b"ab", EOL, b"cd", EOL, b"ef"].concat());
+            assert!(complete);
+        }
+        // EOR and EOF in one descriptor
+        let (out, _, complete) = decode(&mut Decoder::new(Mode::Block, Structure::Record, false), &[DESC_EOR | DESC_EOF, 0, 0], 3);
+        assert_eq!(out, EOL);
+        assert!(complete);
+    }
+
+    #[test]
+    fn stream_record_escape() {
+        let data = b"a\xFFb\nc";
+        let wire = encode(&mut Encoder::new(Mode::Stream, Structure::Record, false, 0), data, 2);
+        assert_eq!(wire, [b'a', 0xFF, 0xFF, b'b', 0xFF, STREAM_EOR, b'c', 0xFF, STREAM_EOF]);
+        for chunk in [1, 2, wire.len()] {
+            let (out, _, complete) = decode(&mut Decoder::new(Mode::Stream, Structure::Record, false), &wire, chunk);
+            assert_eq!(out, [b"a\xFFb", EOL, b"c"].concat());
+            assert!(complete);
+        }
+        // 0xFF 0x03 is EOR and EOF
+        let (out, _, complete) = decode(&mut Decoder::new(Mode::Stream, Structure::Record, false), b"x\xFF\x03", 1);
+        assert_eq!(out, [b"x", EOL].concat());
+        assert!(complete);
+        // closed after an escape
+        let (_, _, complete) = decode(&mut Decoder::new(Mode::Stream, Structure::Record, false), b"x\xFF", 1);
+        assert!(!complete);
+    }
+
+    #[test]
+    fn crlf_across_chunks() {
+        // a CRLF pair split between two reads of the local file stays one pair
+        let mut encoder = Encoder::new(Mode::Stream, Structure::File, true, 0);
+        let mut wire = encoder.encode(b"a\r");
+        wire.extend(encoder.encode(b"\nb\n"));
+        assert_eq!(wire, b"a\r\nb\r\n");
+        // a lone CR is kept
+        assert_eq!(Encoder::new(Mode::Stream, Structure::File, true, 0).encode(b"a\rb"), b"a\rb");
+
+        // records end at the split pair, a lone CR is record data
+        let mut encoder = Encoder::new(Mode::Stream, Structure::Record, true, 0);
+        let mut wire = encoder.encode(b"a\r");
+        wire.extend(encoder.encode(b"\nb\r"));
+        wire.extend(encoder.encode(b"c\r"));
+        wire.extend(encoder.finish());
+        assert_eq!(wire, [b'a', 0xFF, STREAM_EOR, b'b', CR, b'c', CR, 0xFF, STREAM_EOF]);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn crlf_decode_across_chunks() {
+        let mut decoder = Decoder::new(Mode::Stream, Structure::File, true);
+        let mut out = Vec::new();
+        decoder.decode(b"a\r", &mut out);
+        decoder.decode(b"\nb\r", &mut out);
+        decoder.decode(b"c\r", &mut out);
+        assert!(decoder.finish(&mut out));
+        assert_eq!(out, b"a\nb\rc\r");
+        // the same in block mode, split inside the block
+        let wire = encode(&mut Encoder::new(Mode::Block, Structure::File, true, 0), b"x\ny\n", 10);
+        let (out, _, _) = decode(&mut Decoder::new(Mode::Block, Structure::File, true), &wire, 1);
+        assert_eq!(out, b"x\ny\n");
+    }
+}
@@ -18,7 +18,7 @@ enum Commands {
     #[command(subcommand)]
     Tftp(TftpSub),
     #[command()]
-    Ftp,
+    Ftp(ftp::ftpd::Config),
     #[command()]
     Syslog,
     #[command()]
@@ -42,7 +42,16 @@ enum TftpSub {
         dport: u16,
         /// transfer mode
         #[arg(default_value = "octet")]
-        mode: String
+        mode: String,
+        /// expected SHA-256 of the file
+        #[arg(long)]
+        sha256: Option<String>,
+        /// fetch the expected SHA-256 from <file>.sha256 on the server
+        #[arg(long, conflicts_with = "sha256")]
+        sidecar: bool,
+        /// keep a mismatched file as <file>.quarantine instead of deleting it
+        #[arg(long)]
+        quarantine: bool
     },
     #[command()]
     Put {
@@ -57,7 +66,13 @@ enum TftpSub {
         dport: u16,
         /// transfer mode
         #[arg(short, default_value = "octet")]
-        mode: String
+        mode: String,
+        /// expected SHA-256 of the file
+        #[arg(long)]
+        sha256: Option<String>,
+        /// fetch the expected SHA-256 from <file>.sha256 on the server
+        #[arg(long, conflicts_with = "sha256")]
+        sidecar: bool
     }
 }
 
@@ -71,18 +86,22 @@ impl Commands {
                     Listen => {
                         tftp::tftpd::run()
                     },
-                    Get { dst, file, dport, mode } => {
-                        if let Err(e) = tftp::tftpc::get(dst, file, dport, mode) {
+                    Get { dst, file, dport, mode, sha256, sidecar, quarantine } => {
+                        let verify = tftp::tftpc::Verify { sha256, sidecar, quarantine };
+                        if let Err(e) = tftp::tftpc::get(dst, file, dport, mode, verify) {
                             println!("{:?}", e);
                         }
                     },
-                    Put { dst, file, dport, mode } => {
-                        tftp::tftpc::put(dst, file, dport, mode)
+                    Put { dst, file, dport, mode, sha256, sidecar } => {
+                        let verify = tftp::tftpc::Verify { sha256, sidecar, quarantine: false };
+                        if let Err(e) = tftp::tftpc::put(dst, file, dport, mode, verify) {
+                            println!("{:?}", e);
+                        }
                     },
                 }
             },
-            Ftp => {
-                ftp::ftpd::run();
+            Ftp(config) => {
+                ftp::ftpd::run(config);
             },
             Syslog => {
                 syslog::syslogd::run();
@@ -1,6 +1,10 @@
 use std::io;
-use std::net::{Ipv4Addr, UdpSocket};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
+use sha2::{Digest, Sha256};
 
 const NUL: u8 = 0;
 const OP_RRQ: u8 = 1;
@@ -11,43 +15,309 @@ const OP_ERROR: u8 = 5;
 const MAX_RETRY: i32 = 5;
 const TIMEOUT: Option<Duration> = Some(Duration::new(5, 0));
 
-pub fn get(dst: Ipv4Addr, file: String, dport: u16, mode: String) -> io::Result<()> {
-    let mut recv_buf = [0u8; 1024];
-    let mut rrq_buf = vec![NUL, OP_RRQ];
-    rrq_buf.extend(file.as_bytes());
-    rrq_buf.push(NUL);
-    rrq_buf.extend(mode.as_bytes());
-    rrq_buf.push(NUL);
-    let rrq_buf = rrq_buf.as_slice();
-
-    let socket = UdpSocket::bind("127.0.0.1:0").expect("Ephemeral port is not available");
-    socket.set_read_timeout(TIMEOUT).expect("set_read_timeout call failed");
-    socket.set_write_timeout(TIMEOUT).expect("set_write_timeout call failed");
-    socket.send_to(rrq_buf, format!("{}:{}", dst, dport))?;
-
-    match socket.recv_from(&mut recv_buf) {
-        Ok((number_of_bytes, src_addr)) => {
-            let filled_buf = &recv_buf[..number_of_bytes];
-            println!("{:?}", src_addr);
-            println!("{:?}", filled_buf);
+/// Integrity check applied after a transfer.
+/// TFTP itself has no checksum beyond UDP's.
+#[derive(Debug, Default)]
+pub struct Verify {
+    /// expected SHA-256 as hex
+    pub sha256: Option<String>,
+    /// fetch `<file>.sha256` from the same server
+    pub sidecar: bool,
+    /// keep a mismatched file as `<file>.quarantine` instead of deleting it
+    pub quarantine: bool,
+}
+
+pub fn get(dst: Ipv4Addr, file: String, dport: u16, mode: String, verify: Verify) -> io::Result<()> {
+    let server = SocketAddr::from((dst, dport));
+    // Resolve the digest first, nothing is saved when the sidecar cannot be read.
+    let expected = expected_digest(server, &file, &verify)?;
+    let data = fetch(server, &file, &mode)?;
+
+    // Save under the base name in the current directory, through <file>.part
+    // so a bad download never replaces an existing file.
+    let local = local_path(&file);
+    let mut part = local.clone().into_os_string();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    File::create(&part)?.write_all(&data)?;
+
+    if let Some(expected) = &expected {
+        let actual = sha256_hex(&data);
+        if &actual != expected {
+            if verify.quarantine {
+                let mut quarantine = local.clone().into_os_string();
+                quarantine.push(".quarantine");
+                fs::rename(&part, &quarantine)?;
+                println!("Quarantined {:?}", quarantine);
+            } else {
+                fs::remove_file(&part)?;
+                println!("Deleted {:?}", part);
+            }
+            return Err(mismatch(expected, &actual))
+        }
+        println!("SHA-256 verified: {}", actual);
+    }
+    fs::rename(&part, &local)?;
+    println!("Received {} bytes into {:?}", data.len(), local);
+    Ok(())
+}
+
+pub fn put(dst: Ipv4Addr, file: String, dport: u16, mode: String, verify: Verify) -> io::Result<()> {
+    let server = SocketAddr::from((dst, dport));
+    let mut data = Vec::new();
+    File::open(&file)?.read_to_end(&mut data)?;
+    let remote = local_path(&file).to_string_lossy().into_owned();
+
+    // Refuse to upload a source that is already corrupted.
+    let expected = expected_digest(server, &remote, &verify)?;
+    if let Some(expected) = &expected {
+        let actual = sha256_hex(&data);
+        if &actual != expected {
+            return Err(mismatch(expected, &actual))
+        }
+    }
+
+    let socket = open_socket()?;
+    socket.send_to(&build_request(OP_WRQ, &remote, &mode), server)?;
+
+    // The server answers ACK 0 from its transfer port.
+    let mut recv_buf = [0u8; 516];
+    let tid = match recv_packet(&socket, &mut recv_buf, None) {
+        Ok((n, src_addr)) => {
+            check_ack(&recv_buf[..n], 0)?;
+            src_addr
         },
         Err(e) => {
-            println!("Failed to receive the first DATA packet: {:?}", e);
+            println!("Failed to receive the first ACK packet: {:?}", e);
             return Err(e)
         }
+    };
+
+    let data = if mode.eq_ignore_ascii_case("netascii") { to_netascii(&data) } else { data };
+    // A final empty DATA packet is needed when the size is a multiple of 512.
+    let mut blocks: Vec<&[u8]> = data.chunks(512).collect();
+    if data.len() % 512 == 0 {
+        blocks.push(&[]);
+    }
+
+    for (i, chunk) in blocks.into_iter().enumerate() {
+        let block = (i + 1) as u16;
+        let mut packet = vec![NUL, OP_DATA];
+        packet.extend(block.to_be_bytes());
+        packet.extend(chunk);
+
+        let mut retry_count = 0;
+        loop {
+            socket.send_to(&packet, tid)?;
+            match recv_packet(&socket, &mut recv_buf, Some(tid)) {
+                Ok((n, _)) if check_ack(&recv_buf[..n], block)? => break,
+                Ok(_) => (),
+                Err(e) => println!("recv function failed: {:?}", e)
+            }
+            retry_count += 1;
+            if retry_count >= MAX_RETRY {
+                return Err(io::Error::new(io::ErrorKind::NotConnected,
+                    "The maximum number of retries has been reached."))
+            }
+        }
     }
+    println!("Sent {} bytes as {:?}", data.len(), remote);
 
+    // Read the upload back to make sure the server stored what we sent.
+    if let Some(expected) = expected {
+        let actual = sha256_hex(&fetch(server, &remote, &mode)?);
+        if actual != expected {
+            return Err(mismatch(&expected, &actual))
+        }
+        println!("SHA-256 verified: {}", actual);
+    }
     Ok(())
 }
 
+/// Download a file into memory.
+fn fetch(server: SocketAddr, file: &str, mode: &str) -> io::Result<Vec<u8>> {
+    let socket = open_socket()?;
+    let rrq_buf = build_request(OP_RRQ, file, mode);
+    socket.send_to(&rrq_buf, server)?;
+
+    let mut file_buf = Vec::new();
+    let mut recv_buf = [0u8; 516];
+    let mut block = 1u16;
+    let mut tid: Option<SocketAddr> = None;
+    let mut retry_count = 0;
+
+    loop {
+        let (n, src_addr) = match recv_packet(&socket, &mut recv_buf, tid) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("recv function failed: {:?}", e);
+                retry_count += 1;
+                if retry_count >= MAX_RETRY {
+                    return Err(io::Error::new(io::ErrorKind::NotConnected,
+                        "The maximum number of retries has been reached."))
+                }
+                // Resend the request or the last ACK.
+                match tid {
+                    Some(tid) => { socket.send_to(&build_ack(block.wrapping_sub(1)), tid)?; },
+                    None => { socket.send_to(&rrq_buf, server)?; }
+                }
+                continue
+            }
+        };
+        let packet = &recv_buf[..n];
+        if packet.len() < 4 {
+            continue
+        }
+        if packet[1] == OP_ERROR {
+            return Err(error_packet(packet))
+        }
+        if packet[1] != OP_DATA || packet[2..4] != block.to_be_bytes() {
+            continue
+        }
+
+        tid = Some(src_addr);
+        retry_count = 0;
+        socket.send_to(&build_ack(block), src_addr)?;
+
+        let data = &packet[4..];
+        file_buf.extend(data);
+        if data.len() < 512 {
+            break
+        }
+        block = block.wrapping_add(1);
+    }
+
+    if mode.eq_ignore_ascii_case("netascii") {
+        file_buf = from_netascii(&file_buf);
+    }
+    Ok(file_buf)
+}
+
+/// Resolve the expected digest from the argument or the sidecar file.
+fn expected_digest(server: SocketAddr, file: &str, verify: &Verify) -> io::Result<Option<String>> {
+    if let Some(v) = &verify.sha256 {
+        return parse_digest(v).map(Some)
+    }
+    if !verify.sidecar {
+        return Ok(None)
+    }
+    // Sidecar format follows sha256sum: "<hex>  <name>"
+    let sidecar = fetch(server, &format!("{}.sha256", file), "octet")?;
+    let sidecar = String::from_utf8_lossy(&sidecar);
+    match sidecar.split_whitespace().next() {
+        Some(v) => parse_digest(v).map(Some),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "Empty sidecar file."))
+    }
+}
+
+fn parse_digest(value: &str) -> io::Result<String> {
+    let value = value.trim().to_ascii_lowercase();
+    if value.len() != 64 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid SHA-256 digest."))
+    }
+    Ok(value)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn mismatch(expected: &str, actual: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+        format!("SHA-256 mismatch: expected {}, got {}", expected, actual))
+}
+
+fn open_socket() -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(TIMEOUT)?;
+    socket.set_write_timeout(TIMEOUT)?;
+    Ok(socket)
+}
+
+/// Receive a packet, dropping anything from an unexpected transfer ID.
+fn recv_packet(socket: &UdpSocket, buf: &mut [u8], tid: Option<SocketAddr>) -> io::Result<(usize, SocketAddr)> {
+    loop {
+        let (n, src_addr) = socket.recv_from(buf)?;
+        match tid {
+            Some(tid) if tid != src_addr => {
+                // RFC1350: an unknown TID gets error 5 and does not disturb the transfer.
+                let _ = socket.send_to(&build_err_packet(5u8, "Unknown transfer ID."), src_addr);
+            },
+            _ => return Ok((n, src_addr))
+        }
+    }
+}
 
+fn check_ack(packet: &[u8], block: u16) -> io::Result<bool> {
+    if packet.len() >= 2 && packet[1] == OP_ERROR {
+        return Err(error_packet(packet))
+    }
+    Ok(packet.len() >= 4 && packet[1] == OP_ACK && packet[2..4] == block.to_be_bytes())
+}
 
+fn local_path(file: &str) -> PathBuf {
+    match Path::new(file).file_name() {
+        Some(v) => PathBuf::from(v),
+        None => PathBuf::from(file)
+    }
+}
 
+// LF -> CR LF, CR -> CR NUL
+fn to_netascii(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len());
+    for &v in buf {
+        match v {
+            10u8 => out.extend([13u8, 10u8]),
+            13u8 => out.extend([13u8, 0u8]),
+            _ => out.push(v)
+        }
+    }
+    out
+}
 
-pub fn put(dst: Ipv4Addr, file: String, dport: u16, mode: String) {
-    let socket = UdpSocket::bind("127.0.0.1:0").expect("Ephemeral port is not available");
-    socket.set_read_timeout(TIMEOUT).expect("set_read_timeout call failed");
-    socket.set_write_timeout(TIMEOUT).expect("set_write_timeout call failed");
-    socket.connect(format!("{}:{}", dst, dport)).expect("connect function failed");
+// CR LF -> LF, CR NUL -> CR
+fn from_netascii(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len());
+    let mut iter = buf.iter().copied().peekable();
+    while let Some(v) = iter.next() {
+        if v == 13u8 {
+            match iter.peek() {
+                Some(&10u8) => { iter.next(); out.push(10u8); continue },
+                Some(&0u8) => { iter.next(); }
+                _ => ()
+            }
+        }
+        out.push(v);
+    }
+    out
+}
+
+fn build_request(opcode: u8, file: &str, mode: &str) -> Vec<u8> {
+    let mut packet = vec![NUL, opcode];
+    packet.extend(file.as_bytes());
+    packet.push(NUL);
+    packet.extend(mode.as_bytes());
+    packet.push(NUL);
+    packet
+}
+
+fn build_ack(block: u16) -> Vec<u8> {
+    let mut packet = vec![NUL, OP_ACK];
+    packet.extend(block.to_be_bytes());
+    packet
+}
+
+fn build_err_packet(code: u8, msg: &str) -> Vec<u8> {
+    let mut packet = vec![NUL, OP_ERROR, NUL];
+    packet.push(code);
+    packet.extend(msg.as_bytes());
+    packet.push(NUL);
+    packet
+}
 
-}
\ No newline at end of file
+fn error_packet(packet: &[u8]) -> io::Error {
+    let msg = packet.get(4..).unwrap_or_default();
+    let msg = String::from_utf8_lossy(msg);
+    io::Error::other(
+        format!("TFTP error {}: {}", packet.get(3).unwrap_or(&0), msg.trim_end_matches('\0')))
+}